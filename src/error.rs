@@ -0,0 +1,402 @@
+use std::{fmt, path::PathBuf, process::ExitStatus};
+
+/// Errors the pipeline stages return for bad input, a bad legend, or missing
+/// files. The messages are the ones the stages used to log before panicking.
+#[derive(Debug)]
+pub enum PipelineError {
+    TraitNotFound(String),
+    MultipleTraitRows(String),
+    MissingColumn {
+        column:     String,
+        trait_name: String,
+    },
+    NaColumn {
+        column:     String,
+        trait_name: String,
+    },
+    InvalidDelimiter(String),
+    InvalidEffectType(String),
+    LegendColumnsMissing {
+        mismatches: Vec<String>,
+        header:     Vec<String>,
+    },
+    RawInputDirRequired,
+    RawInputDirMissing(PathBuf),
+    RawInputDirNotDir(PathBuf),
+    NotAbsolute(PathBuf),
+    RawInputMissing(PathBuf),
+    RawInputNotFile(PathBuf),
+    PreReaderFailed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    EmptyInput(PathBuf),
+    NoRowsLeft(PathBuf),
+    NegativeStandardErrors(usize),
+    XSampleSizeColumnMissing(String),
+    StudyDuplicates {
+        collapsed: usize,
+        examples:  Vec<String>,
+    },
+    HeaderCollision {
+        column: String,
+        legend: String,
+    },
+    NoPositionColumns,
+    ChainMissing {
+        from: String,
+        to:   String,
+        path: PathBuf,
+    },
+    InvalidChain {
+        path: PathBuf,
+        line: usize,
+    },
+    CytobandMissing(PathBuf),
+    InvalidCytoband {
+        path: PathBuf,
+        line: usize,
+    },
+    NoDbsnpFiles(String),
+    InvalidDbsnpGlob(String),
+    DbsnpColumnsMissing(Vec<String>),
+    DbsnpShardHeader {
+        shard:    PathBuf,
+        expected: Vec<String>,
+        found:    Vec<String>,
+    },
+    DbsnpDuplicates(usize),
+    EffectUnitsNotBeta,
+    InvalidTargetAllele(String),
+    EafFillPopulationMissing,
+    AfColumnMissing {
+        column: String,
+        option: &'static str,
+    },
+    InconsistentAlleles {
+        row:       usize,
+        unique_id: String,
+        ref_:      String,
+        alt:       String,
+    },
+    IntermediateMissing(PathBuf),
+    IntermediateColumnsMissing {
+        path:    PathBuf,
+        missing: Vec<String>,
+    },
+    IntermediateHeaders {
+        merged:  Vec<String>,
+        missing: Vec<String>,
+    },
+    OutputExists(Vec<String>),
+    OutputTruncated(usize),
+    OutputRowNotJson(usize),
+    OutputHeaderMismatch {
+        expected: Vec<String>,
+        found:    Vec<String>,
+    },
+    OutputRowCount {
+        expected: usize,
+        found:    usize,
+    },
+    Samtools(std::io::Error),
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TraitNotFound(trait_name) => {
+                write!(
+                    f,
+                    "No rows found in the GWAS formatting legend for trait_name={}",
+                    trait_name
+                )
+            },
+            Self::MultipleTraitRows(trait_name) => {
+                write!(
+                    f,
+                    "Multiple rows found in the GWAS formatting legend for trait_name={}",
+                    trait_name
+                )
+            },
+            Self::MissingColumn { column, trait_name } => {
+                write!(
+                    f,
+                    "Column {} is missing in the GWAS formatting legend for trait_name={}",
+                    column, trait_name
+                )
+            },
+            Self::NaColumn { column, trait_name } => {
+                write!(
+                    f,
+                    "Column {} is NA in the GWAS formatting legend for trait_name={}",
+                    column, trait_name
+                )
+            },
+            Self::InvalidDelimiter(delim) => write!(f, "Invalid column delimiter {}", delim),
+            Self::InvalidEffectType(effect_type) => {
+                write!(
+                    f,
+                    "effect_type {} in the GWAS formatting legend must be beta, or, or z",
+                    effect_type
+                )
+            },
+            Self::LegendColumnsMissing { mismatches, header } => {
+                write!(
+                    f,
+                    "Columns named in the GWAS formatting legend are not in the raw data file: {} \
+                     (header: {})",
+                    mismatches.join(", "),
+                    header.join(", ")
+                )
+            },
+            Self::RawInputDirRequired => {
+                write!(
+                    f,
+                    "--raw-input-dir is required when --raw-path-base is raw-dir"
+                )
+            },
+            Self::RawInputDirMissing(path) => {
+                write!(f, "Raw input directory {} does not exist", path.display())
+            },
+            Self::RawInputDirNotDir(path) => {
+                write!(
+                    f,
+                    "Raw input directory {} is not a directory",
+                    path.display()
+                )
+            },
+            Self::NotAbsolute(path) => {
+                write!(
+                    f,
+                    "file_path {} in the GWAS formatting legend is not an absolute path",
+                    path.display()
+                )
+            },
+            Self::RawInputMissing(path) => {
+                write!(f, "Raw input file {} does not exist", path.display())
+            },
+            Self::RawInputNotFile(path) => {
+                write!(f, "Raw input file {} is not a file", path.display())
+            },
+            Self::PreReaderFailed { status, stderr } => {
+                write!(f, "Pre-reader failed ({}): {}", status, stderr.trim_end())
+            },
+            Self::EmptyInput(path) => {
+                write!(
+                    f,
+                    "Raw data file {} has no data rows, pass --allow-empty to write an empty \
+                     output",
+                    path.display()
+                )
+            },
+            Self::NoRowsLeft(path) => {
+                write!(
+                    f,
+                    "Every variant in raw data file {} was removed in preformatting, pass \
+                     --allow-empty to write an empty output",
+                    path.display()
+                )
+            },
+            Self::NegativeStandardErrors(negative) => {
+                write!(
+                    f,
+                    "{} standard errors are negative. Please check that the effect size and \
+                     standard error columns are not swapped",
+                    negative
+                )
+            },
+            Self::XSampleSizeColumnMissing(col) => {
+                write!(
+                    f,
+                    "--x-sample-size-column {} is not in the raw data file",
+                    col
+                )
+            },
+            Self::StudyDuplicates {
+                collapsed,
+                examples,
+            } => {
+                write!(
+                    f,
+                    "The study lists the same chr/pos/ref/alt more than once ({} rows, e.g. {})",
+                    collapsed,
+                    examples.join(", ")
+                )
+            },
+            Self::HeaderCollision { column, legend } => {
+                write!(
+                    f,
+                    "Column {} (legend {}) appears more than once after renaming",
+                    column, legend
+                )
+            },
+            Self::NoPositionColumns => write!(f, "No position columns found in the raw data file"),
+            Self::ChainMissing { from, to, path } => {
+                write!(
+                    f,
+                    "Chain file {} for {} to {} does not exist, pass its location with \
+                     --chain-{}-to-{} <path> or --chain {}:{}:<path>",
+                    path.display(),
+                    from,
+                    to,
+                    from,
+                    to,
+                    from,
+                    to
+                )
+            },
+            Self::InvalidChain { path, line } => {
+                write!(
+                    f,
+                    "Chain file {} is malformed at line {}",
+                    path.display(),
+                    line
+                )
+            },
+            Self::CytobandMissing(path) => {
+                write!(f, "Cytoband file {} does not exist", path.display())
+            },
+            Self::InvalidCytoband { path, line } => {
+                write!(
+                    f,
+                    "Cytoband file {} is malformed at line {}, expected chrom, start, end, and \
+                     band name",
+                    path.display(),
+                    line
+                )
+            },
+            Self::NoDbsnpFiles(path) => write!(f, "No dbSNP files found at {}", path),
+            Self::InvalidDbsnpGlob(pattern) => {
+                write!(f, "Invalid dbSNP file pattern {}", pattern)
+            },
+            Self::DbsnpColumnsMissing(cols) => {
+                write!(
+                    f,
+                    "Columns are missing from the dbSNP file: {}",
+                    cols.join(", ")
+                )
+            },
+            Self::DbsnpShardHeader {
+                shard,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "dbSNP shard {} header does not match the first shard (expected {}, found {})",
+                    shard.display(),
+                    expected.join(", "),
+                    found.join(", ")
+                )
+            },
+            Self::DbsnpDuplicates(duplicates) => {
+                write!(f, "dbSNP contains {} duplicate records", duplicates)
+            },
+            Self::EffectUnitsNotBeta => {
+                write!(f, "--effect-units sd only applies to beta effect sizes")
+            },
+            Self::InvalidTargetAllele(line) => {
+                write!(f, "Target alleles must be chr:pos:ref:alt, found {}", line)
+            },
+            Self::EafFillPopulationMissing => {
+                write!(
+                    f,
+                    "--eaf-fill study-then-gnomad needs a population or --primary-af"
+                )
+            },
+            Self::AfColumnMissing { column, option } => {
+                write!(f, "Column {} for {} is not present", column, option)
+            },
+            Self::InconsistentAlleles {
+                row,
+                unique_id,
+                ref_,
+                alt,
+            } => {
+                write!(
+                    f,
+                    "Variant {} (row {}) has inconsistent alleles after harmonization: ref={} \
+                     alt={}",
+                    unique_id, row, ref_, alt
+                )
+            },
+            Self::IntermediateMissing(path) => {
+                write!(f, "Intermediate file {} does not exist", path.display())
+            },
+            Self::IntermediateColumnsMissing { path, missing } => {
+                write!(
+                    f,
+                    "Intermediate file {} is missing columns: {}",
+                    path.display(),
+                    missing.join(", ")
+                )
+            },
+            Self::IntermediateHeaders { merged, missing } => {
+                write!(
+                    f,
+                    "Intermediate files have different headers (merged: {}, missing: {})",
+                    merged.join(", "),
+                    missing.join(", ")
+                )
+            },
+            Self::OutputExists(existing) => {
+                write!(
+                    f,
+                    "Output files already exist, refusing to overwrite them with --no-clobber: {}",
+                    existing.join(", ")
+                )
+            },
+            Self::OutputTruncated(rows) => {
+                write!(
+                    f,
+                    "Failed to read the output after {} rows, it may be truncated",
+                    rows
+                )
+            },
+            Self::OutputRowNotJson(row) => write!(f, "Output row {} is not a JSON object", row),
+            Self::OutputHeaderMismatch { expected, found } => {
+                write!(
+                    f,
+                    "Output header does not match (expected {}, found {})",
+                    expected.join(", "),
+                    found.join(", ")
+                )
+            },
+            Self::OutputRowCount { expected, found } => {
+                write!(
+                    f,
+                    "Output row count does not match (expected {}, found {})",
+                    expected, found
+                )
+            },
+            Self::Samtools(e) => write!(f, "Failed to run samtools: {}", e),
+            Self::Sqlite(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Samtools(e) | Self::Io(e) => Some(e),
+            Self::Sqlite(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PipelineError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for PipelineError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
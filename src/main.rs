@@ -1,12 +1,23 @@
+mod error;
+
 use std::{
-    collections::{HashMap, HashSet},
-    io::Write,
-    mem::MaybeUninit,
-    path::Path,
-    sync::Mutex,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
 use clap::Parser;
+use error::PipelineError;
+use gzp::{
+    deflate::Gzip,
+    par::compress::{ParCompress, ParCompressBuilder},
+    ZWriter,
+};
+use rand::SeedableRng;
 use rayon::prelude::*;
 use tracing::{debug, error, info, warn};
 
@@ -53,35 +64,695 @@ const ASSIGN_COL_NAMES: [&str; 13] = [
 #[derive(Clone, Debug, clap::Parser)]
 #[command(version)]
 pub struct Args {
-    #[arg(short, long)]
-    google_sheets_id:    String,
-    #[arg(short, long)]
-    trait_name:          String,
+    #[arg(short, long, required_unless_present_any = ["legend_url", "legend_file"])]
+    google_sheets_id: Option<String>,
+    /// Traits to process; with more than one, `--output-file` must contain a
+    /// `{trait_name}` placeholder
+    #[arg(short, long, num_args = 1.., required = true)]
+    trait_name: Vec<String>,
     #[arg(short = 'i', long)]
-    raw_input_dir:       String,
-    #[arg(short, long)]
-    liftover:            String,
+    raw_input_dir: Option<String>,
     #[arg(long)]
-    liftover_dir:        String,
+    liftover_dir: String,
     #[arg(short = 'r', long)]
-    grs_dir:             String,
-    #[arg(short, long)]
-    dbsnp_file:          String,
+    grs_dir: String,
+    /// The dbSNP file, or a directory or glob of shards with matching headers
     #[arg(short, long)]
-    samtools:            String,
+    dbsnp_file: String,
     #[arg(short, long)]
-    fasta_ref:           String,
+    samtools: String,
     #[arg(short, long)]
-    output_file:         String,
+    fasta_ref: String,
     #[arg(short, long)]
-    samtools_threads:    Option<usize>,
+    output_file: String,
+    #[arg(long)]
+    samtools_threads: Option<usize>,
     #[arg(short = 'c', long)]
     samtools_chunk_size: Option<usize>,
+    /// Number of significant digits to use for derived numeric values
+    #[arg(long)]
+    precision: Option<usize>,
+    /// Path of the JSON run report, defaults to `<output_file>.report.json`
+    #[arg(long)]
+    report_file: Option<String>,
+    /// Compare dbSNP match rates under the stated and alternative builds
+    #[arg(long)]
+    check_build: bool,
+    /// How variants that are not found in dbSNP are joined into the output
+    #[arg(long, value_enum, default_value_t = DbsnpJoin::Left)]
+    dbsnp_join: DbsnpJoin,
+    /// Output a `harmonization` column recording how each variant was oriented
+    #[arg(long)]
+    harmonization_column: bool,
+    /// How the legend's `file_path` is resolved
+    #[arg(long, value_enum, default_value_t = RawPathBase::RawDir)]
+    raw_path_base: RawPathBase,
+    /// Allele frequency columns carried over from the dbSNP file
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values = ["gnomAD_AF_EUR", "gnomAD_AF_AMR", "gnomAD_AF_AFR", "gnomAD_AF_EAS", "gnomAD_AF_SAS"]
+    )]
+    af_columns: Vec<String>,
+    /// Only load the dbSNP columns needed for matching and annotation
+    #[arg(long)]
+    dbsnp_chunk_columns: bool,
+    /// Run single-threaded and deduplicate in a stable order so repeated runs
+    /// produce identical output
+    #[arg(long)]
+    deterministic: bool,
+    /// UCSC cytoBand file used to annotate each variant with its band
+    #[arg(long)]
+    cytoband: Option<String>,
+    /// Write intermediate files into this directory, defaulting to the current
+    /// directory; may contain a `{trait_name}` placeholder
+    #[arg(long, num_args = 0..=1, default_missing_value = ".")]
+    keep_intermediates: Option<String>,
+    /// Compression used for intermediate files
+    #[arg(long, value_enum, default_value_t = Compression::Gzip)]
+    intermediate_compression: Compression,
+    /// Shell command that converts the raw input file, passed as its last
+    /// argument, to TSV on stdout
+    #[arg(long)]
+    pre_reader: Option<String>,
+    /// What to do with variants whose effect size is NA or infinite
+    #[arg(long, value_enum, default_value_t = NaEffect::Drop)]
+    na_effect: NaEffect,
+    /// Coerce integral floating point positions like `12345.0` to integers
+    #[arg(long)]
+    repair_positions: bool,
+    /// Ancestry whose gnomAD allele frequency populates an `AF` column, e.g.
+    /// `EUR`
+    #[arg(long)]
+    primary_af: Option<String>,
+    /// Drop the per-ancestry allele frequency columns from the output
+    #[arg(long)]
+    drop_ancestry_af: bool,
+    /// Keep processing the remaining traits when one fails
+    #[arg(long)]
+    continue_on_error: bool,
+    /// Write a JSON summary of the succeeded and failed traits to this path
+    #[arg(long)]
+    batch_summary: Option<String>,
+    /// Also write `<output>.plotsub.txt.gz` with every variant below the
+    /// p-value threshold plus a random sample of the rest, as
+    /// `<p-threshold>:<sample-n>`
+    #[arg(long, value_parser = parse_plot_subset)]
+    plot_subset: Option<(f64, usize)>,
+    /// Print the resolved GWAS formatting legend row for each trait
+    #[arg(long)]
+    dump_legend: bool,
+    /// Stop after resolving and validating the GWAS formatting legend
+    #[arg(long)]
+    dry_run: bool,
+    /// Output this build's coordinates as `chr`/`pos` and the other build's as
+    /// `chr_other`/`pos_other`
+    #[arg(long, value_enum)]
+    primary_build: Option<Build>,
+    /// Drop the secondary build's coordinates when using `--primary-build`
+    #[arg(long, requires = "primary_build")]
+    drop_secondary_build: bool,
+    /// Format of the output file
+    #[arg(long, value_enum, default_value_t = OutputFormat::Tsv)]
+    output_format: OutputFormat,
+    /// Table written to when `--output-format sqlite`, defaults to the trait
+    /// name
+    #[arg(long)]
+    sqlite_table: Option<String>,
+    /// Retry unmatched non-palindromic SNPs against dbSNP with complemented
+    /// alleles
+    #[arg(long)]
+    strand_flip_with_complement: bool,
+    /// Drop strand-ambiguous A/T and C/G SNPs before matching dbSNP
+    #[arg(long, conflicts_with = "resolve_palindromic_by_freq")]
+    drop_palindromic: bool,
+    /// Decide the strand of matched A/T and C/G SNPs by which orientation
+    /// puts EAF closer to this dbSNP allele frequency column
+    #[arg(long)]
+    resolve_palindromic_by_freq: Option<String>,
+    /// Report a coarse histogram of the EAF column for QC
+    #[arg(long)]
+    report_allele_frequency_histogram: bool,
+    /// Drop variants whose liftover moved them more than this many base pairs
+    /// or across chromosomes
+    #[arg(long)]
+    max_liftover_shift: Option<u64>,
+    /// Output a `source` column recording whether each variant was matched in
+    /// dbSNP or ref checked
+    #[arg(long)]
+    source_column: bool,
+    /// Format sample sizes that are integral (within a small tolerance) as
+    /// integers
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    integer_n: bool,
+    /// Flip variants to match the ref/alt of a list of chr:pos:ref:alt target
+    /// alleles
+    #[arg(long)]
+    target_alleles: Option<String>,
+    /// The build of the positions in --target-alleles
+    #[arg(long, value_enum, default_value_t = Build::Hg38, requires = "target_alleles")]
+    target_build: Build,
+    /// Drop variants that are not in --target-alleles
+    #[arg(long, requires = "target_alleles")]
+    restrict_to_target: bool,
+    /// Convert p-values that look like they are on a -log10 or ln scale back to
+    /// p-values
+    #[arg(long)]
+    assume_logp: bool,
+    /// How variants that share a unique_id are handled
+    #[arg(long, value_enum, default_value_t = DuplicateIds::Drop)]
+    duplicate_ids: DuplicateIds,
+    /// What the effect size column holds, overriding the legend's `effect_type`
+    /// or `effect_is_OR`
+    #[arg(long, value_enum)]
+    effect_type: Option<EffectType>,
+    /// Reconstruct the effect size and standard error of z-score effects from
+    /// EAF and N_total
+    #[arg(long)]
+    reconstruct_effect: bool,
+    /// Drop variants in the extended MHC region (chr6:25-35 Mb) of the output
+    /// build
+    #[arg(long)]
+    exclude_mhc: bool,
+    /// Stream the raw input file and keep only the columns named in the legend
+    #[arg(long)]
+    low_memory: bool,
+    /// Re-read the written output and check its header and row count
+    #[arg(long)]
+    verify_output: bool,
+    /// Where the output EAF comes from: study, gnomad-<pop>, or
+    /// study-then-gnomad[-<pop>] (defaulting to the --primary-af
+    /// population)
+    #[arg(long, value_parser = parse_eaf_fill, default_value = "study")]
+    eaf_fill: EafFill,
+    /// Number of traits to process concurrently, each in its own work directory
+    #[arg(long, default_value_t = 1)]
+    parallel_traits: usize,
+    /// URL of the legend published as CSV, e.g. a Google Sheets
+    /// `export?format=csv` link
+    #[arg(long, conflicts_with = "google_sheets_id")]
+    legend_url: Option<String>,
+    /// Drop variants whose N_total is below this
+    #[arg(long)]
+    min_n: Option<f64>,
+    /// Also drop variants with an NA N_total when filtering with --min-n
+    #[arg(long, requires = "min_n")]
+    require_n: bool,
+    /// Seed for random sampling, or `random` to seed from OS entropy
+    #[arg(long, value_parser = parse_seed, default_value = "0")]
+    seed: Seed,
+    /// Fail instead of warning when QC finds a likely column swap, such as
+    /// negative standard errors
+    #[arg(long)]
+    fail_on_warn: bool,
+    /// Log and report which raw columns were mapped or dropped and which
+    /// canonical columns are NA
+    #[arg(long)]
+    columns_report: bool,
+    /// Raw column holding X chromosome sample sizes, used as N_total for chrX
+    /// variants. Only N_total is overridden; N_case and N_ctrl keep the
+    /// autosomal values and no dosage modeling is done
+    #[arg(long)]
+    x_sample_size_column: Option<String>,
+    /// How dbSNP records sharing a chr/pos/ref/alt are resolved
+    #[arg(long, value_enum, default_value_t = DbsnpDup::First)]
+    dbsnp_dup: DbsnpDup,
+    /// Identifier used in the SNP column of --output-format score
+    #[arg(long, value_enum, default_value_t = ScoreId::Rsid)]
+    score_id: ScoreId,
+    /// Normalize rsids in the raw data and dbSNP to `rs<digits>`
+    #[arg(long, value_enum, default_value_t = RsidNormalization::None)]
+    rsid_normalization: RsidNormalization,
+    /// Fail if the output, report, or any intermediate file already exists
+    #[arg(long, conflicts_with = "force")]
+    no_clobber: bool,
+    /// Overwrite existing output files (the default)
+    #[arg(long)]
+    force: bool,
+    /// Write `##key=val` provenance lines above the TSV header, along with the
+    /// trait name, build, and tool version
+    #[arg(long, value_delimiter = ',', num_args = 0.., value_parser = parse_key_val)]
+    output_meta: Option<Vec<(String, String)>>,
+    /// Fill missing study EAF with a weighted average of the gnomAD
+    /// populations, e.g. EUR=0.7,AFR=0.2,AMR=0.1
+    #[arg(long, value_delimiter = ',', value_parser = parse_ancestry_weight, conflicts_with = "eaf_fill")]
+    ancestry_weights: Option<Vec<(String, f64)>>,
+    /// Skip straight to the ref/alt check using the raw_data_merged and
+    /// raw_data_missing intermediates in this directory; may contain a
+    /// `{trait_name}` placeholder
+    #[arg(long)]
+    from_intermediates: Option<String>,
+    /// Flag continuous-trait variants whose standard error is off from the
+    /// one expected from N and EAF by more than this factor, in an
+    /// `se_anomalous` column
+    #[arg(long)]
+    se_check: Option<f64>,
+    /// Error when a canonical column appears more than once after the legend
+    /// renames instead of keeping the legend's column and suffixing the rest
+    #[arg(long)]
+    strict_header: bool,
+    /// Chain file for a build transition as <from>:<to>:<path>, e.g.
+    /// hg19:hg38:/refs/chains/19to38.chain.gz, overriding the conventional
+    /// name under --liftover-dir
+    #[arg(long, value_parser = parse_chain)]
+    chain: Vec<(String, String, String)>,
+    /// Chain file lifting hg38 to hg19, shorthand for --chain hg38:hg19:<path>
+    #[arg(long)]
+    chain_hg38_to_hg19: Option<String>,
+    /// Chain file lifting hg19 to hg38, shorthand for --chain hg19:hg38:<path>
+    #[arg(long)]
+    chain_hg19_to_hg38: Option<String>,
+    /// Chain file lifting hg18 to hg19, shorthand for --chain hg18:hg19:<path>
+    #[arg(long)]
+    chain_hg18_to_hg19: Option<String>,
+    /// Chain file lifting hg17 to hg19, shorthand for --chain hg17:hg19:<path>
+    #[arg(long)]
+    chain_hg17_to_hg19: Option<String>,
+    /// Chain file lifting hg16 to hg19, shorthand for --chain hg16:hg19:<path>
+    #[arg(long)]
+    chain_hg16_to_hg19: Option<String>,
+    /// Chain file lifting hg38 to CHM13 (hs1), shorthand for --chain
+    /// hg38:hs1:<path>
+    #[arg(long)]
+    chain_hg38_to_hs1: Option<String>,
+    /// Write the variants whose orientation changed during dbSNP matching or
+    /// the ref/alt check, with their alleles and effect before and after, to
+    /// `*.flipped.txt.gz`
+    #[arg(long)]
+    flipped_output: bool,
+    /// Compare each variant's position with dbSNP's position for its input
+    /// rsid, writing the distance into a `pos_discrepant` column
+    #[arg(long)]
+    coordinate_validation: bool,
+    /// Match variants that fail to match dbSNP by position and alleles on
+    /// their input rsid instead, taking dbSNP's coordinates
+    #[arg(long)]
+    match_by_rsid: bool,
+    /// Threads used to gzip output files; 1 uses the single-threaded encoder
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    compress_threads: u32,
+    /// Write an empty but well-formed output for a raw file with no data
+    /// rows instead of failing
+    #[arg(long)]
+    allow_empty: bool,
+    /// Estimated dbSNP memory, in GB, above which only the dbSNP records at
+    /// the study's positions are loaded instead of the whole file
+    #[arg(long)]
+    dbsnp_memory_limit: Option<f64>,
+    /// Fill missing p-values from the effect size and standard error, then
+    /// from z-scores, recording the method in a `pvalue_source` column
+    #[arg(long)]
+    impute_pvalue: bool,
+    /// Check that each indel's ref allele matches the FASTA reference at its
+    /// hg38 position, flagging mismatches in `indel_ref_match` or dropping them
+    #[arg(long, value_enum)]
+    validate_indel_ref: Option<IndelRefCheck>,
+    /// Run the whole pipeline but only write the report, not the output
+    #[arg(
+        long,
+        conflicts_with_all = ["keep_intermediates", "verify_output", "plot_subset", "flipped_output"]
+    )]
+    summary_only: bool,
+    /// Units the study reports effects in; sd effects are multiplied, along
+    /// with their standard errors, by --trait-sd to put them in raw units
+    #[arg(long, value_enum, default_value_t = EffectUnits::Raw)]
+    effect_units: EffectUnits,
+    /// Standard deviation of the trait in raw units, for --effect-units sd
+    #[arg(long, required_if_eq("effect_units", "sd"))]
+    trait_sd: Option<f64>,
+    /// Stop after this stage, writing its output next to the output file (or
+    /// into --keep-intermediates) along with the report
+    #[arg(long, value_enum)]
+    stop_after: Option<Stage>,
+    /// What to do with study rows sharing a chr/pos/ref/alt, e.g. listed
+    /// twice under different rsids
+    #[arg(long, value_enum, default_value_t = StudyDedup::None)]
+    study_dedup: StudyDedup,
+    /// Write the variants dropped in preformatting for an unparseable
+    /// position or effect size, or an odds ratio with no log, with the
+    /// offending column and value, to `*.parse_errors.txt.gz`
+    #[arg(long)]
+    keep_failed_parse_rows: bool,
+    /// Chromosome naming in the output: bare (1, X), chr-prefix (chr1, chrX),
+    /// or numeric (1, 23)
+    #[arg(long, value_enum, default_value_t = ChrOutput::Bare)]
+    chr_output: ChrOutput,
+    /// Size of the thread pool for the parallel processing stages, all cores by
+    /// default. samtools threads are set separately with --samtools-threads
+    #[arg(long, conflicts_with = "deterministic")]
+    threads: Option<usize>,
+    /// Read the legend from a local TSV or CSV file (optionally gzipped)
+    /// instead of Google Sheets
+    #[arg(long, conflicts_with_all = ["google_sheets_id", "legend_url"])]
+    legend_file: Option<String>,
+    /// Google Sheets API key, used when GWAS_SHEETS_API_KEY is not set
+    #[arg(long)]
+    api_key: Option<String>,
+    /// Title of the Google Sheets tab holding the legend, the first tab by
+    /// default
+    #[arg(long, conflicts_with_all = ["legend_url", "legend_file"])]
+    sheet_name: Option<String>,
+    /// Times to retry a legend request that fails with a 5xx or 429 status or a
+    /// network error, waiting 1s, 2s, 4s, ... in between
+    #[arg(long, default_value_t = 3)]
+    http_retries: u32,
+    /// Raw input columns to carry through to the output, after the standard
+    /// columns
+    #[arg(long, value_delimiter = ',')]
+    keep_columns: Vec<String>,
+    /// Write the rows that failed liftover, with the reason, to this file
+    /// (`{trait_name}` is replaced)
+    #[arg(long)]
+    unlifted_output: Option<String>,
+    /// Also lift the hg38 positions to another assembly and output them as
+    /// chr_<build>/pos_<build>
+    #[arg(long, value_enum)]
+    lift_to: Option<LiftTarget>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChrOutput {
+    Bare,
+    ChrPrefix,
+    Numeric,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StudyDedup {
+    None,
+    First,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Stage {
+    Preformat,
+    Liftover,
+    Dbsnp,
+    Refalt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EffectUnits {
+    Raw,
+    Sd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum IndelRefCheck {
+    Flag,
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RsidNormalization {
+    /// Leave rsids as they are
+    None,
+    /// Normalize rsids and keep other identifiers as they are
+    KeepOther,
+    /// Normalize rsids and set other identifiers to NA
+    DropOther,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScoreId {
+    Rsid,
+    /// chr:pos:ref:alt on the --primary-build, or hg38
+    Variant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DbsnpDup {
+    /// Keep the record that appears first in the dbSNP file
+    First,
+    /// Keep the record that appears last in the dbSNP file
+    Last,
+    /// Fail on duplicate records
+    Error,
+    /// Keep the first record with the rsids of all of them joined by `;`
+    Merge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EffectType {
+    Beta,
+    Or,
+    /// A signed z-score with no standard error
+    Z,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicateIds {
+    /// Keep the first variant and drop the rest
+    Drop,
+    /// Keep every variant, appending a counter to the unique_id of repeats
+    Suffix,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Gzipped tab-separated values
+    Tsv,
+    /// A table in a SQLite database, indexed on position and rsid
+    Sqlite,
+    /// Gzipped tab-separated values in the GWAS Catalog harmonized format on
+    /// hg38
+    GwasCatalog,
+    /// A PLINK `--score` file of SNP, effect allele, other allele, and effect
+    Score,
+    /// Gzipped newline-delimited JSON, one object per variant with numeric
+    /// columns as numbers and NA as null
+    Ndjson,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Build {
+    Hg19,
+    Hg38,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LiftTarget {
+    /// T2T-CHM13 (hs1), lifted from hg38 with hg38ToHs1.over.chain.gz
+    Chm13,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum NaEffect {
+    /// Remove the variant
+    Drop,
+    /// Keep the variant with `effect_size=NA`
+    Keep,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RawPathBase {
+    /// Relative to `--raw-input-dir`, ignoring a single leading `/`
+    RawDir,
+    /// Relative to the current working directory
+    Cwd,
+    /// As an absolute path
+    Absolute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DbsnpJoin {
+    /// Only keep variants found in dbSNP
+    Inner,
+    /// Keep variants not found in dbSNP that pass the reference check, with NA
+    /// annotations
+    Left,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EafFill {
+    Study,
+    Gnomad(String),
+    StudyThenGnomad(Option<String>),
+}
+
+fn parse_eaf_fill(s: &str) -> Result<EafFill, String> {
+    if s == "study" {
+        Ok(EafFill::Study)
+    } else if s == "study-then-gnomad" {
+        Ok(EafFill::StudyThenGnomad(None))
+    } else if let Some(pop) = s.strip_prefix("study-then-gnomad-") {
+        Ok(EafFill::StudyThenGnomad(Some(pop.to_string())))
+    } else if let Some(pop) = s.strip_prefix("gnomad-") {
+        Ok(EafFill::Gnomad(pop.to_string()))
+    } else {
+        Err("expected study, gnomad-<pop>, or study-then-gnomad[-<pop>]".to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Seed {
+    Fixed(u64),
+    Random,
+}
+
+fn parse_seed(s: &str) -> Result<Seed, String> {
+    if s == "random" {
+        Ok(Seed::Random)
+    } else {
+        s.parse::<u64>().map(Seed::Fixed).map_err(|e| e.to_string())
+    }
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let (k, v) = s
+        .split_once('=')
+        .ok_or_else(|| "expected key=val".to_string())?;
+    Ok((k.to_string(), v.to_string()))
+}
+
+fn parse_ancestry_weight(s: &str) -> Result<(String, f64), String> {
+    let (pop, w) = parse_key_val(s)?;
+    let w = w.parse::<f64>().map_err(|e| e.to_string())?;
+    if w.is_nan() || w < 0.0 {
+        return Err("weights must be non-negative".to_string());
+    }
+    Ok((pop, w))
+}
+
+fn parse_chain(s: &str) -> Result<(String, String, String), String> {
+    let mut parts = s.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(from), Some(to), Some(path)) if !path.is_empty() => {
+            Ok((from.to_string(), to.to_string(), path.to_string()))
+        },
+        _ => Err("expected <from>:<to>:<path>".to_string()),
+    }
+}
+
+fn parse_plot_subset(s: &str) -> Result<(f64, usize), String> {
+    let (p, n) = s
+        .split_once(':')
+        .ok_or_else(|| "expected <p-threshold>:<sample-n>".to_string())?;
+    let p = p.parse::<f64>().map_err(|e| e.to_string())?;
+    let n = n.parse::<usize>().map_err(|e| e.to_string())?;
+    Ok((p, n))
 }
 
 pub struct Ctx {
-    args:  Args,
-    sheet: Data,
+    args:        Args,
+    sheet:       Data,
+    report:      Report,
+    trait_name:  String,
+    output_file: String,
+}
+
+#[derive(Default)]
+pub struct Report(Mutex<serde_json::Map<String, serde_json::Value>>);
+
+impl Report {
+    pub fn set(&self, key: &str, value: impl Into<serde_json::Value>) {
+        self.0.lock().unwrap().insert(key.to_string(), value.into());
+    }
+
+    pub fn set_in(&self, section: &str, key: &str, value: impl Into<serde_json::Value>) {
+        let mut report = self.0.lock().unwrap();
+        let section = report
+            .entry(section)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        section[key] = value.into();
+    }
+
+    pub fn write(&self, name: impl AsRef<Path>) {
+        let file = std::fs::File::create(name).unwrap();
+        serde_json::to_writer_pretty(file, &*self.0.lock().unwrap()).unwrap();
+    }
+}
+
+impl Ctx {
+    /// Threads used to gzip output files, from --compress-threads
+    pub fn compress_threads(&self) -> usize {
+        self.args.compress_threads as usize
+    }
+
+    /// Chain file lifting `from` to `to`, either given with its
+    /// --chain-<from>-to-<to> flag or --chain, or the conventional UCSC
+    /// name under --liftover-dir.
+    pub fn chain_path(&self, from: &str, to: &str) -> Result<PathBuf, PipelineError> {
+        let pair_flag = match (from, to) {
+            ("hg38", "hg19") => self.args.chain_hg38_to_hg19.as_ref(),
+            ("hg19", "hg38") => self.args.chain_hg19_to_hg38.as_ref(),
+            ("hg18", "hg19") => self.args.chain_hg18_to_hg19.as_ref(),
+            ("hg17", "hg19") => self.args.chain_hg17_to_hg19.as_ref(),
+            ("hg16", "hg19") => self.args.chain_hg16_to_hg19.as_ref(),
+            ("hg38", "hs1") => self.args.chain_hg38_to_hs1.as_ref(),
+            _ => None,
+        };
+        let chain = self
+            .args
+            .chain
+            .iter()
+            .find(|(f, t, _)| f == from && t == to)
+            .map(|(_, _, path)| path);
+        let path = match pair_flag.or(chain) {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let mut to = to.to_string();
+                to[..1].make_ascii_uppercase();
+                Path::new(&self.args.liftover_dir).join(format!("{}To{}.over.chain.gz", from, to))
+            },
+        };
+        if !path.exists() {
+            return Err(PipelineError::ChainMissing {
+                from: from.to_string(),
+                to: to.to_string(),
+                path,
+            });
+        }
+        Ok(path)
+    }
+
+    /// Path next to the output file with its extension replaced by `suffix`.
+    pub fn output_sibling(&self, suffix: &str) -> String {
+        let base = self
+            .output_file
+            .strip_suffix(".gz")
+            .unwrap_or(&self.output_file);
+        let base = base
+            .strip_suffix(".txt")
+            .or_else(|| base.strip_suffix(".tsv"))
+            .unwrap_or(base);
+        format!("{}.{}", base, suffix)
+    }
+
+    /// Table the SQLite output is written to.
+    pub fn sqlite_table(&self) -> &str {
+        self.args.sqlite_table.as_ref().unwrap_or(&self.trait_name)
+    }
+
+    /// RNG for sampling, seeded from --seed. The seed is reported so random
+    /// runs can be reproduced.
+    pub fn rng(&self) -> rand::rngs::StdRng {
+        let seed = match self.args.seed {
+            Seed::Fixed(seed) => seed,
+            Seed::Random => rand::random(),
+        };
+        self.report.set("seed", seed);
+        rand::rngs::StdRng::seed_from_u64(seed)
+    }
+
+    pub fn fmt_f64(&self, v: f64) -> String {
+        match self.args.precision {
+            Some(p) if v.is_finite() => {
+                format!("{:.*e}", p.max(1) - 1, v)
+                    .parse::<f64>()
+                    .unwrap()
+                    .to_string()
+            },
+            _ => v.to_string(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -101,6 +772,20 @@ impl Data {
         self.header.iter().position(|x| x == key)
     }
 
+    /// Returns the index of a column, appending it filled with NA if it is
+    /// missing
+    pub fn idx_or_push(&mut self, key: &str) -> usize {
+        if let Some(idx) = self.idx_opt(key) {
+            return idx;
+        }
+        self.header.push(key.to_string());
+        let header_len = self.header.len();
+        self.data
+            .par_iter_mut()
+            .for_each(|r| r.resize(header_len, "NA".to_string()));
+        header_len - 1
+    }
+
     pub fn col(&self, key: &str) -> impl Iterator<Item = &'_ str> {
         let idx = self.idx(key);
         self.data.iter().map(move |x| x[idx].as_str())
@@ -130,17 +815,119 @@ impl Data {
         self.data.iter_mut().map(move |x| &mut x[idx])
     }
 
-    pub fn write(&self, name: impl AsRef<Path>) {
-        let file = std::fs::File::create(name).unwrap();
-        let mut writer = flate2::write::GzEncoder::new(&file, flate2::Compression::default());
+    pub fn write(&self, name: impl AsRef<Path>, threads: usize) {
+        let header = self.header.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        self.write_with_header_order(name, threads, &header, &[]);
+    }
+
+    /// Writes the given columns in the given order, projecting each row as it
+    /// is written rather than reordering the stored rows first. The file is
+    /// gzipped only when its name ends in `.gz`, with `threads` compressing.
+    pub fn write_with_header_order(
+        &self,
+        name: impl AsRef<Path>,
+        threads: usize,
+        header: &[&str],
+        comments: &[String],
+    ) {
+        write_file(name, threads, |writer| {
+            self.write_to_with_header_order(writer, header, comments)
+        });
+    }
+
+    /// Writes `comments` as `##` lines, `header`, and then each row as the
+    /// iterator produces it, so the rows never have to be collected. Returns
+    /// the number of rows written.
+    pub fn write_rows<R: AsRef<[String]>>(
+        name: impl AsRef<Path>,
+        threads: usize,
+        header: &[&str],
+        comments: &[String],
+        rows: impl Iterator<Item = R>,
+    ) -> usize {
+        let mut count = 0;
+        write_file(name, threads, |writer| {
+            for c in comments {
+                writeln!(writer, "##{}", c).unwrap();
+            }
+            writeln!(writer, "{}", header.join("\t")).unwrap();
+            for r in rows {
+                writeln!(writer, "{}", r.as_ref().join("\t")).unwrap();
+                count += 1;
+            }
+        });
+        count
+    }
+
+    pub fn write_to(&self, writer: impl Write) {
+        let header = self.header.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        self.write_to_with_header_order(writer, &header, &[]);
+    }
+
+    /// Writes `comments` as `##` lines above the header
+    pub fn write_to_with_header_order(
+        &self,
+        mut writer: impl Write,
+        header: &[&str],
+        comments: &[String],
+    ) {
         debug!(len = self.data.len(), "Writing rows",);
-        writeln!(writer, "{}", self.header.join("\t")).unwrap();
+        for c in comments {
+            writeln!(writer, "##{}", c).unwrap();
+        }
+        writeln!(writer, "{}", header.join("\t")).unwrap();
+        if header.iter().eq(self.header.iter()) {
+            for r in &self.data {
+                writeln!(writer, "{}", r.join("\t")).unwrap();
+            }
+            return;
+        }
+        let idxs = header.iter().map(|x| self.idx_opt(x)).collect::<Vec<_>>();
         for r in &self.data {
-            writeln!(writer, "{}", r.join("\t")).unwrap();
+            for (i, idx) in idxs.iter().enumerate() {
+                if i > 0 {
+                    writer.write_all(b"\t").unwrap();
+                }
+                let v = idx.map_or("NA", |idx| r[idx].as_str());
+                writer.write_all(v.as_bytes()).unwrap();
+            }
+            writer.write_all(b"\n").unwrap();
+        }
+    }
+
+    /// Returns a copy with only the given columns, in the given order, filling
+    /// columns that are not present with `NA`.
+    pub fn select(&self, cols: &[&str]) -> Data {
+        let idxs = cols.iter().map(|x| self.idx_opt(x)).collect::<Vec<_>>();
+        let data = self
+            .data
+            .par_iter()
+            .map(|r| {
+                idxs.iter()
+                    .map(|idx| {
+                        match idx {
+                            Some(idx) => r[*idx].clone(),
+                            None => "NA".to_string(),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        Data {
+            header: cols.iter().map(|x| x.to_string()).collect(),
+            data,
+        }
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) {
+        for h in self.header.iter_mut().filter(|x| *x == from) {
+            *h = to.to_string();
         }
-        writer.finish().unwrap();
     }
 
+    /// Like [`Data::select`], but moves the values instead of copying them.
+    /// Every row is rebuilt with the columns in `new_order`, filling NA for
+    /// columns the header does not have.
     #[track_caller]
     pub fn reorder(&mut self, new_order: &[&str]) {
         let new_order_idxs = new_order
@@ -169,7 +956,12 @@ impl Data {
         let mut raw = String::new();
         file.read_to_string(&mut raw).unwrap();
         let (header, content) = if has_header {
-            let (header, content) = raw.split_once('\n').unwrap();
+            // skip `##` metadata lines, such as the ones --output-meta writes
+            let mut raw = raw.as_str();
+            while raw.starts_with("##") {
+                raw = raw.split_once('\n').map_or("", |(_, rest)| rest);
+            }
+            let (header, content) = raw.split_once('\n').unwrap_or((raw, ""));
             let header = header
                 .split(delim)
                 // .map(|x| unsafe { String::from_raw_parts(x.as_ptr().cast_mut(), x.len(), x.len()) })
@@ -193,915 +985,4221 @@ impl Data {
         // Data { raw, header, data }
         Data { header, data }
     }
-}
 
-fn read_raw_data(delim: &str, file: impl std::io::Read) -> Data {
-    let delim = if delim == "\t" || delim == "tab" {
-        '\t'
-    } else if delim == "," || delim == "comma" {
-        ','
-    } else if delim == "space" {
-        ' '
-    } else {
-        error!("Invalid column delimiter {}", delim);
-        panic!();
-    };
-    Data::read(delim, file, true)
+    /// Reads only the columns in `cols`, line by line instead of holding the
+    /// whole file in memory, so peak memory is just the projected rows
+    pub fn read_lines_projected(delim: char, file: impl std::io::Read, cols: &[&str]) -> Self {
+        let mut lines = std::io::BufReader::new(file)
+            .lines()
+            .map(|x| x.unwrap())
+            .skip_while(|x| x.starts_with("##"));
+        let header = lines.next().unwrap_or_default();
+        let header = header.strip_prefix('\u{feff}').unwrap_or(&header);
+        let header = header.split(delim).collect::<Vec<_>>();
+        let idxs = cols
+            .iter()
+            .filter_map(|c| header.iter().position(|x| x == c))
+            .collect::<Vec<_>>();
+        let data = lines
+            .map(|x| {
+                let r = x.split(delim).collect::<Vec<_>>();
+                idxs.iter()
+                    .map(|i| r.get(*i).copied().unwrap_or("NA").to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let header = idxs.iter().map(|i| header[*i].to_string()).collect();
+        Data { header, data }
+    }
 }
 
-fn reserve_to(r: &mut Vec<String>, len: usize) -> usize {
-    let n = len - r.len();
-    if let Some(res) = len.checked_sub(r.capacity()) {
-        r.reserve_exact(res);
-    }
-    n
+type Interval<T> = (u64, u64, T);
+
+pub struct IntervalIndex<T> {
+    // intervals sorted by start, alongside the running maximum end
+    chroms: HashMap<String, (Vec<Interval<T>>, Vec<u64>)>,
 }
 
-#[tracing::instrument(skip(ctx))]
-fn preformat(ctx: &Ctx) -> Data {
-    let rows = ctx
-        .sheet
-        .matching_rows("trait_name", |x| x == ctx.args.trait_name)
-        .collect::<Vec<_>>();
-    if rows.is_empty() {
-        error!(
-            "No rows found in the GWAS formatting legend for trait_name={}",
-            ctx.args.trait_name
-        );
-        panic!();
-    }
-    if rows.len() > 1 {
-        error!(
-            "Multiple rows found in the GWAS formatting legend for trait_name={}",
-            ctx.args.trait_name
-        );
-        panic!();
-    }
-    let row = rows[0];
-    for col in COLS_MUST_BE_PRESENT.iter() {
-        let val = ctx.sheet.get_from_row(row, col);
-        if val.is_empty() {
-            error!(
-                "Column {} is missing in the GWAS formatting legend for trait_name={}",
-                col, ctx.args.trait_name
-            );
-            panic!();
+impl<T> IntervalIndex<T> {
+    /// Builds an index over half-open `[start, end)` intervals.
+    pub fn new(intervals: impl IntoIterator<Item = (String, u64, u64, T)>) -> Self {
+        let mut chroms = HashMap::<String, (Vec<Interval<T>>, Vec<u64>)>::new();
+        for (chr, start, end, v) in intervals {
+            chroms.entry(chr).or_default().0.push((start, end, v));
         }
-    }
-    for col in COLS_MUST_NOT_BE_NA.iter() {
-        let val = ctx.sheet.get_from_row(row, col);
-        if val == "NA" || val == "NaN" {
-            error!(
-                "Column {} is NA in the GWAS formatting legend for trait_name={}",
-                col, ctx.args.trait_name
-            );
-            panic!();
+        for (intervals, max_ends) in chroms.values_mut() {
+            intervals.sort_by_key(|x| x.0);
+            let mut max_end = 0;
+            *max_ends = intervals
+                .iter()
+                .map(|x| {
+                    max_end = max_end.max(x.1);
+                    max_end
+                })
+                .collect();
         }
+        IntervalIndex { chroms }
     }
-    let raw_input_dir = std::path::Path::new(&ctx.args.raw_input_dir);
-    if !raw_input_dir.exists() {
-        error!(
-            "Raw input directory {} does not exist",
-            ctx.args.raw_input_dir
-        );
-        panic!();
-    }
-    if !raw_input_dir.is_dir() {
-        error!(
-            "Raw input directory {} is not a directory",
-            ctx.args.raw_input_dir
-        );
-        panic!();
+
+    /// Returns every interval on `chr` containing `pos`.
+    pub fn find(&self, chr: &str, pos: u64) -> impl Iterator<Item = &'_ Interval<T>> {
+        let (intervals, max_ends) = match self.chroms.get(chr) {
+            Some((intervals, max_ends)) => (intervals.as_slice(), max_ends.as_slice()),
+            None => (&[][..], &[][..]),
+        };
+        let end = intervals.partition_point(|x| x.0 <= pos);
+        (0..end)
+            .rev()
+            .take_while(move |i| max_ends[*i] > pos)
+            .map(move |i| &intervals[i])
+            .filter(move |x| x.1 > pos)
     }
-    let mut file_path = ctx.sheet.get_from_row(row, "file_path").as_str();
-    if file_path.starts_with('/') {
-        file_path = file_path.strip_prefix('/').unwrap();
+}
+
+fn parse_delim(delim: &str) -> Result<char, PipelineError> {
+    if delim == "\t" || delim == "tab" {
+        Ok('\t')
+    } else if delim == "," || delim == "comma" {
+        Ok(',')
+    } else if delim == "space" {
+        Ok(' ')
+    } else {
+        Err(PipelineError::InvalidDelimiter(delim.to_string()))
     }
-    let raw_input_file = raw_input_dir.join(file_path);
-    if !raw_input_file.exists() {
-        error!(
-            "Raw input file {} does not exist",
-            raw_input_file.to_string_lossy()
-        );
-        panic!();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+/// Creates a file and hands a buffered writer for it to `write`, gzipping when
+/// the name ends in `.gz`.
+fn write_file(name: impl AsRef<Path>, threads: usize, write: impl FnOnce(&mut dyn Write)) {
+    if name.as_ref().extension().is_some_and(|x| x == "gz") {
+        return write_gz(name, threads, write);
     }
-    if !raw_input_file.is_file() {
-        error!(
-            "Raw input file {} is not a file",
-            raw_input_file.to_string_lossy()
-        );
-        panic!();
+    let file = std::fs::File::create(name).unwrap();
+    let mut writer = std::io::BufWriter::new(file);
+    write(&mut writer);
+    writer.flush().unwrap();
+}
+
+/// Creates a gzipped file and hands a buffered writer for it to `write`, using
+/// the parallel encoder when `threads` is above 1.
+fn write_gz(name: impl AsRef<Path>, threads: usize, write: impl FnOnce(&mut dyn Write)) {
+    let file = std::fs::File::create(name).unwrap();
+    if threads > 1 {
+        // pigz-style block compression, still a single standard gzip stream
+        let mut encoder: ParCompress<Gzip, _> = ParCompressBuilder::new()
+            .num_threads(threads)
+            .unwrap()
+            .from_writer(file);
+        let mut writer = std::io::BufWriter::new(&mut encoder);
+        write(&mut writer);
+        writer.flush().unwrap();
+        drop(writer);
+        encoder.finish().unwrap();
+        return;
     }
-    info!(raw_input_file = %raw_input_file.to_string_lossy(), "Reading raw input file");
-    let gz = raw_input_file.to_string_lossy().ends_with(".gz");
-    let delim = ctx.sheet.get_from_row(row, "column_delim");
-    let file = std::fs::File::open(&raw_input_file).unwrap();
-    let mut raw_data = if gz {
-        let gz = flate2::read::GzDecoder::new(file);
-        read_raw_data(delim, gz)
-    } else {
-        read_raw_data(delim, file)
-    };
-    debug!(header = ?raw_data.header, "Header");
-    for col in ASSIGN_COL_NAMES.iter() {
-        let val = ctx.sheet.get_from_row(row, col);
-        if val != "NA" {
-            for r in raw_data.header.iter_mut() {
-                if r == val {
-                    *r = col.to_string();
+    let writer = flate2::write::GzEncoder::new(&file, flate2::Compression::default());
+    let mut writer = std::io::BufWriter::new(writer);
+    write(&mut writer);
+    writer.into_inner().unwrap().finish().unwrap();
+}
+
+/// Infers the narrowest type that every non-`NA` value in each column parses
+/// as.
+fn column_types(data: &Data) -> Vec<ColumnType> {
+    (0..data.header.len())
+        .into_par_iter()
+        .map(|i| {
+            let mut ty = ColumnType::Integer;
+            for r in &data.data {
+                let v = r[i].as_str();
+                if v == "NA" {
+                    continue;
+                }
+                if ty == ColumnType::Integer && v.parse::<i64>().is_err() {
+                    ty = ColumnType::Real;
+                }
+                if ty == ColumnType::Real && v.parse::<f64>().is_err() {
+                    return ColumnType::Text;
                 }
             }
+            ty
+        })
+        .collect()
+}
+
+/// Writes one JSON object per row, typing the columns like [`write_sqlite`].
+/// The objects are written by hand so the keys keep the column order.
+#[tracing::instrument(skip(data))]
+fn write_ndjson(data: &Data, path: &str, threads: usize) {
+    let types = column_types(data);
+    let keys = data
+        .header
+        .iter()
+        .map(|x| serde_json::to_string(x).unwrap())
+        .collect::<Vec<_>>();
+    write_file(path, threads, |writer| {
+        for r in &data.data {
+            let fields = r.iter().zip(&types).zip(&keys).map(|((v, t), k)| {
+                let v = if v == "NA" {
+                    serde_json::Value::Null
+                } else {
+                    match t {
+                        ColumnType::Integer => v.parse::<i64>().unwrap().into(),
+                        ColumnType::Real => {
+                            serde_json::Number::from_f64(v.parse().unwrap())
+                                .map_or(serde_json::Value::Null, serde_json::Value::Number)
+                        },
+                        ColumnType::Text => v.as_str().into(),
+                    }
+                };
+                format!("{}:{}", k, v)
+            });
+            writeln!(writer, "{{{}}}", fields.collect::<Vec<_>>().join(",")).unwrap();
+        }
+    });
+}
+
+#[tracing::instrument(skip(data))]
+fn write_sqlite(data: &Data, path: &str, table: &str) {
+    let types = column_types(data);
+    let mut conn = rusqlite::Connection::open(path).unwrap();
+    let tx = conn.transaction().unwrap();
+    let columns = data
+        .header
+        .iter()
+        .zip(&types)
+        .map(|(h, t)| {
+            let t = match t {
+                ColumnType::Integer => "INTEGER",
+                ColumnType::Real => "REAL",
+                ColumnType::Text => "TEXT",
+            };
+            format!("\"{}\" {}", h, t)
+        })
+        .collect::<Vec<_>>();
+    tx.execute(&format!("DROP TABLE IF EXISTS \"{}\"", table), [])
+        .unwrap();
+    tx.execute(
+        &format!("CREATE TABLE \"{}\" ({})", table, columns.join(", ")),
+        [],
+    )
+    .unwrap();
+    {
+        let placeholders = vec!["?"; data.header.len()].join(", ");
+        let mut stmt = tx
+            .prepare(&format!(
+                "INSERT INTO \"{}\" VALUES ({})",
+                table, placeholders
+            ))
+            .unwrap();
+        for r in &data.data {
+            let values = r.iter().zip(&types).map(|(v, t)| {
+                if v == "NA" {
+                    rusqlite::types::Value::Null
+                } else {
+                    match t {
+                        ColumnType::Integer => rusqlite::types::Value::Integer(v.parse().unwrap()),
+                        ColumnType::Real => rusqlite::types::Value::Real(v.parse().unwrap()),
+                        ColumnType::Text => rusqlite::types::Value::Text(v.clone()),
+                    }
+                }
+            });
+            stmt.execute(rusqlite::params_from_iter(values)).unwrap();
         }
     }
-    debug!(header = ?raw_data.header, "Header");
-    for chr in raw_data.col_mut("chr") {
-        // a) Remove "chr" prefix
-        if let Some(c) = chr.strip_prefix("chr") {
-            *chr = c.to_string();
+    for cols in [
+        &["chr", "pos"][..],
+        &["chr_hg19", "pos_hg19"],
+        &["chr_hg38", "pos_hg38"],
+        &["rsid"],
+    ] {
+        if cols.iter().all(|c| data.header.iter().any(|h| h == c)) {
+            tx.execute(
+                &format!(
+                    "CREATE INDEX \"{}_{}\" ON \"{}\" ({})",
+                    table,
+                    cols.join("_"),
+                    table,
+                    cols.iter()
+                        .map(|c| format!("\"{}\"", c))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                [],
+            )
+            .unwrap();
         }
-        // b) Convert 23-25 to X, Y, M
-        if *chr == "23" {
-            *chr = "X".to_string();
-        } else if *chr == "24" {
-            *chr = "Y".to_string();
-        } else if *chr == "25" {
-            *chr = "M".to_string();
+    }
+    tx.commit().unwrap();
+}
+
+fn read_raw_data(delim: char, file: impl std::io::Read) -> Data {
+    let mut data = Data::read(delim, file, true);
+    // files exported from Windows tools can start with a UTF-8 BOM, which would
+    // otherwise stick to the first column name
+    if let Some(first) = data.header.first_mut() {
+        if let Some(stripped) = first.strip_prefix('\u{feff}') {
+            *first = stripped.to_string();
         }
     }
-    // c) Change alleles to uppercase
-    for r in raw_data.col_mut("ref") {
-        *r = r.to_ascii_uppercase();
+    data
+}
+
+/// Canonicalizes a chromosome name by removing any `chr` prefix and converting
+/// 23-25 to X, Y, M, returning `None` if it is already canonical.
+fn normalize_chr(chr: &str) -> Option<&str> {
+    let c = chr.strip_prefix("chr").unwrap_or(chr);
+    let c = match c {
+        "23" => "X",
+        "24" => "Y",
+        "25" | "MT" => "M",
+        c => c,
+    };
+    (c != chr).then_some(c)
+}
+
+/// Complements a single-nucleotide allele, returning None for anything else
+fn complement(allele: &str) -> Option<&'static str> {
+    match allele {
+        "A" => Some("T"),
+        "T" => Some("A"),
+        "C" => Some("G"),
+        "G" => Some("C"),
+        _ => None,
     }
-    for a in raw_data.col_mut("alt") {
-        *a = a.to_ascii_uppercase();
+}
+
+/// Whether a SNP's alleles are each other's complement (A/T or C/G), so it
+/// reads the same on both strands
+fn is_palindromic(ref_: &str, alt: &str) -> bool {
+    complement(ref_) == Some(alt)
+}
+
+/// Normalizes `RS12345.1 ` style rsids to `rs12345`, returning None for
+/// identifiers that aren't rsids
+fn normalize_rsid(id: &str) -> Option<String> {
+    let id = id.trim();
+    let digits = id.get(..2)?.eq_ignore_ascii_case("rs").then(|| &id[2..])?;
+    let digits = digits.split_once('.').map_or(digits, |(d, _)| d);
+    if digits.is_empty() || !digits.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
     }
-    debug!(len = raw_data.data.len(), "Raw data before d and e");
-    let data = std::mem::take(&mut raw_data.data);
-    raw_data.data = data
-        .into_par_iter()
-        .filter(|x| {
-            let r = raw_data.get_from_row(x.as_slice(), "ref");
-            let a = raw_data.get_from_row(x.as_slice(), "alt");
-            let effect_size = raw_data.get_from_row(x.as_slice(), "effect_size");
-            // debug!(?x, r, a, effect_size, "Checking ref, alt, and effect size");
-            // d) Remove SNPs with ambiguous ref or alt
-            r != "I"
-                && r != "D"
-                && r != "IND"
-                && r != "DEL"
-                && a != "I"
-                && a != "D"
-                && a != "IND"
-                && a != "DEL"
-            // e) Remove variants with nonsensical effect estimates
-                && effect_size != "Nan"
-                && effect_size != "NaN"
-                && effect_size != "NA"
-                && effect_size != "Inf"
-                && effect_size != "-Inf"
-                && effect_size != "inf"
-                && effect_size != "-inf"
-        })
-        .collect::<Vec<_>>();
-    debug!(len = raw_data.data.len(), "Raw data after d and e");
-    // f) Convert OR to beta
-    let effect_is_or = ctx.sheet.get_from_row(row, "effect_is_OR");
-    let effect_sizes = raw_data
-        .col("effect_size")
-        .map(|x| x.parse::<f64>().unwrap())
-        .collect::<Vec<_>>();
-    if effect_is_or == "N" && effect_sizes.iter().all(|x| *x > 0.0) {
-        warn!(
-            "All effect sizes are positive yet effect_is_OR has been set to N. Please double \
-             check that effect estimates from the raw data file are indeed regression \
-             coefficients and not odds ratios"
-        );
+    Some(format!("rs{}", digits))
+}
+
+fn normalize_rsids(ctx: &Ctx, data: &mut Data, name: &str) {
+    if ctx.args.rsid_normalization == RsidNormalization::None {
+        return;
+    }
+    let Some(rsid) = data.idx_opt("rsid") else {
+        return;
+    };
+    let keep_other = ctx.args.rsid_normalization == RsidNormalization::KeepOther;
+    let (changed, other) = data
+        .data
+        .par_iter_mut()
+        .map(|r| normalize_rsid_field(&mut r[rsid], keep_other))
+        .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    report_rsid_normalization(ctx, name, changed, other);
+}
+
+/// Normalizes an rsid field in place, returning 1 in the first position if it
+/// was changed and in the second if it isn't an rsid
+fn normalize_rsid_field(id: &mut String, keep_other: bool) -> (usize, usize) {
+    if id == "NA" {
+        return (0, 0);
+    }
+    match normalize_rsid(id) {
+        Some(x) if x == *id => (0, 0),
+        Some(x) => {
+            *id = x;
+            (1, 0)
+        },
+        None => {
+            if !keep_other {
+                *id = "NA".to_string();
+            }
+            (0, 1)
+        },
+    }
+}
+
+fn report_rsid_normalization(ctx: &Ctx, name: &str, changed: usize, other: usize) {
+    info!(name, changed, other, "Normalized rsids");
+    ctx.report
+        .set_in("rsid_normalization", &format!("{}_changed", name), changed);
+    ctx.report
+        .set_in("rsid_normalization", &format!("{}_other", name), other);
+}
+
+/// Collapses study rows with the same chr/pos/ref/alt into the first of them
+fn dedup_study(ctx: &Ctx, raw_data: &mut Data) -> Result<(), PipelineError> {
+    let cols = ["chr", "pos", "ref", "alt"].map(|x| raw_data.idx(x));
+    let mut seen = HashSet::new();
+    let mut examples = vec![];
+    let before = raw_data.data.len();
+    raw_data.data.retain(|r| {
+        let key = cols.map(|i| r[i].clone());
+        if seen.contains(&key) {
+            if examples.len() < 10 {
+                examples.push(key.join(":"));
+            }
+            false
+        } else {
+            seen.insert(key);
+            true
+        }
+    });
+    let collapsed = before - raw_data.data.len();
+    if collapsed > 0 && ctx.args.study_dedup == StudyDedup::Error {
+        return Err(PipelineError::StudyDuplicates {
+            collapsed,
+            examples,
+        });
     }
-    if effect_is_or == "Y" && effect_sizes.iter().any(|x| *x < 0.0) {
+    if collapsed > 0 {
         warn!(
-            "Some effect sizes are negative yet effect_is_OR has been set to Y. Please double \
-             check that effect estimates from the raw data file are indeed odds or hazard ratios \
-             and not regression coefficients"
+            collapsed,
+            ?examples,
+            "Collapsed study rows with the same chr/pos/ref/alt"
         );
     }
-    if effect_is_or == "Y" {
-        let data = std::mem::take(&mut raw_data.data);
-        let effect_size = raw_data.idx("effect_size");
-        raw_data.data = data
-            .into_par_iter()
-            .zip(effect_sizes)
-            .filter_map(|(mut r, e)| {
-                let l = e.ln();
-                if l.is_nan() || l.is_infinite() {
-                    None
-                } else {
-                    r[effect_size] = l.to_string();
-                    Some(r)
-                }
-            })
+    ctx.report.set("study_duplicates_collapsed", collapsed);
+    Ok(())
+}
+
+/// Rows preformat drops because a numeric field couldn't be used, identified
+/// by their position, alleles, and rsid along with the offending column and
+/// value, for --keep-failed-parse-rows
+struct ParseErrors {
+    cols: Vec<(String, usize)>,
+    rows: Mutex<Vec<Vec<String>>>,
+}
+
+impl ParseErrors {
+    fn new(raw_data: &Data, pos_cols: &[(&str, usize)]) -> Self {
+        let cols = ["rsid", "chr"]
+            .into_iter()
+            .chain(pos_cols.iter().map(|(x, _)| *x))
+            .chain(["ref", "alt", "effect_size"])
+            .filter_map(|x| Some((x.to_string(), raw_data.idx_opt(x)?)))
+            .collect();
+        ParseErrors {
+            cols,
+            rows: Mutex::new(vec![]),
+        }
+    }
+
+    fn push(&self, r: &[String], col: &str) {
+        let mut row = self
+            .cols
+            .iter()
+            .map(|(_, i)| r[*i].clone())
             .collect::<Vec<_>>();
+        let value = self
+            .cols
+            .iter()
+            .find(|(x, _)| x == col)
+            .map_or("NA".to_string(), |(_, i)| r[*i].clone());
+        row.push(col.to_string());
+        row.push(value);
+        self.rows.lock().unwrap().push(row);
     }
-    debug!(len = raw_data.data.len(), "Raw data after f");
-    // g) Tabulate columns for sample sizes
-    for var in ["total", "case", "ctrl"] {
-        let var_col_name = ctx.sheet.get_from_row(row, &format!("N_{}_column", var));
-        let var_value = ctx.sheet.get_from_row(row, &format!("N_{}", var));
-        if var_col_name != "NA" {
-            // rename column if values are present
-            for r in raw_data.header.iter_mut() {
-                if *r == format!("N_{}_column", var) {
-                    *r = format!("N_{}", var);
-                }
-            }
-        } else if var_value != "NA" {
-            // update column
-            for r in raw_data.col_mut(&format!("N_{}", var)) {
-                r.clone_from(var_value);
-            }
-        }
+
+    fn write(self, ctx: &Ctx) {
+        let mut header = self.cols.into_iter().map(|(x, _)| x).collect::<Vec<_>>();
+        header.push("error_column".to_string());
+        header.push("error_value".to_string());
+        let data = Data {
+            header,
+            data: self.rows.into_inner().unwrap(),
+        };
+        let path = ctx.output_sibling("parse_errors.txt.gz");
+        info!(
+            count = data.data.len(),
+            path, "Writing rows that failed to parse"
+        );
+        ctx.report.set("parse_errors", data.data.len());
+        data.write(path, ctx.compress_threads());
     }
-    let na = "NA".to_string();
-    // if no sample sizes indicated and gwas legend input is NA then set all three
-    // columns to NA
-    debug!("g: Adding header");
-    for var in ["total", "case", "ctrl"] {
-        if !raw_data.header.contains(&format!("N_{}", var)) {
-            raw_data.header.push(format!("N_{}", var));
+}
+
+fn is_na_effect(effect_size: &str) -> bool {
+    matches!(
+        effect_size,
+        "Nan" | "NaN" | "NA" | "Inf" | "-Inf" | "inf" | "-inf"
+    )
+}
+
+pub struct AlleleCols {
+    ref_:        usize,
+    alt:         usize,
+    effect_size: usize,
+    eaf:         usize,
+}
+
+impl AlleleCols {
+    pub fn new(data: &Data) -> Self {
+        AlleleCols {
+            ref_:        data.idx("ref"),
+            alt:         data.idx("alt"),
+            effect_size: data.idx("effect_size"),
+            eaf:         data.idx("EAF"),
         }
     }
-    debug!("g: Added header");
-    let header_len = raw_data.header.len();
-    raw_data.data.par_iter_mut().for_each(|r| {
-        let res = reserve_to(r, header_len);
-        for _ in 0..res {
-            r.push(na.clone());
+
+    /// Swaps the ref and alt alleles of a row, negating the effect size and
+    /// complementing the effect allele frequency to match.
+    pub fn flip(&self, ctx: &Ctx, r: &mut [String]) {
+        r.swap(self.ref_, self.alt);
+        if let Ok(es) = r[self.effect_size].parse::<f64>() {
+            r[self.effect_size] = ctx.fmt_f64(-es);
         }
-    });
-    debug!("g: Added NAs");
-    // compile case control or total sample sizes if inoformation is available
-    let n_case = raw_data.idx("N_case");
-    let n_ctrl = raw_data.idx("N_ctrl");
-    let n_total = raw_data.idx("N_total");
-    raw_data.data.par_iter_mut().for_each(|r| {
-        if r[n_case] != "NA" && r[n_ctrl] != "NA" {
-            r[n_total] =
-                (r[n_case].parse::<f64>().unwrap() + r[n_ctrl].parse::<f64>().unwrap()).to_string();
+        if let Ok(e) = r[self.eaf].parse::<f64>().map(|e| 1.0 - e) {
+            if !e.is_nan() {
+                r[self.eaf] = ctx.fmt_f64(e);
+            }
         }
-        if r[n_ctrl] != "NA" && r[n_total] != "NA" && r[n_case] == "NA" {
-            r[n_case] = (r[n_total].parse::<f64>().unwrap() - r[n_ctrl].parse::<f64>().unwrap())
-                .to_string();
+    }
+}
+
+fn reserve_to(r: &mut Vec<String>, len: usize) -> usize {
+    let n = len - r.len();
+    if let Some(res) = len.checked_sub(r.capacity()) {
+        r.reserve_exact(res);
+    }
+    n
+}
+
+/// Finds and validates the GWAS formatting legend row for the trait.
+fn legend_row(ctx: &Ctx) -> Result<&[String], PipelineError> {
+    let rows = ctx
+        .sheet
+        .matching_rows("trait_name", |x| x == ctx.trait_name)
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        return Err(PipelineError::TraitNotFound(ctx.trait_name.clone()));
+    }
+    if rows.len() > 1 {
+        return Err(PipelineError::MultipleTraitRows(ctx.trait_name.clone()));
+    }
+    let row = rows[0];
+    for col in COLS_MUST_BE_PRESENT.iter() {
+        let val = ctx.sheet.get_from_row(row, col);
+        if val.is_empty() {
+            return Err(PipelineError::MissingColumn {
+                column:     col.to_string(),
+                trait_name: ctx.trait_name.clone(),
+            });
         }
-        if r[n_case] != "NA" && r[n_total] != "NA" && r[n_ctrl] == "NA" {
-            r[n_ctrl] = (r[n_total].parse::<f64>().unwrap() - r[n_case].parse::<f64>().unwrap())
-                .to_string();
+    }
+    for col in COLS_MUST_NOT_BE_NA.iter() {
+        let val = ctx.sheet.get_from_row(row, col);
+        if val == "NA" || val == "NaN" {
+            return Err(PipelineError::NaColumn {
+                column:     col.to_string(),
+                trait_name: ctx.trait_name.clone(),
+            });
         }
-    });
-    debug!(len = raw_data.data.len(), "Raw data after g");
-    raw_data.reorder(&[
-        "chr",
-        "pos",
-        "ref",
-        "alt",
-        "EAF",
-        "effect_size",
-        "standard_error",
-        "pvalue",
-        "pvalue_het",
-        "N_total",
-        "N_case",
-        "N_ctrl",
-    ]);
-    let pos = raw_data.idx("pos");
-    let chr = raw_data.idx("chr");
-    let hg_version = ctx.sheet.get_from_row(row, "hg_version");
-    raw_data.header[pos] = format!("pos_{}", hg_version);
-    raw_data.header[chr] = format!("chr_{}", hg_version);
-    debug!(header = ?raw_data.header, "Header");
-    assert_eq!(raw_data.header.len(), raw_data.data[0].len());
-    raw_data
+    }
+    Ok(row)
 }
 
-#[tracing::instrument(skip(ctx, raw_data))]
-fn liftover(ctx: &Ctx, raw_data: &Data) {
-    let current_dir = std::env::current_dir().unwrap();
-    let liftover_dir = std::path::Path::new(&ctx.args.liftover_dir);
-    let mut bed = std::fs::File::create(current_dir.join("input.bed")).unwrap();
-    let pos_hg17 = raw_data.header.contains(&"pos_hg17".to_string());
-    let pos_hg18 = raw_data.header.contains(&"pos_hg18".to_string());
-    let pos_hg19 = raw_data.header.contains(&"pos_hg19".to_string());
-    let pos_hg38 = raw_data.header.contains(&"pos_hg38".to_string());
-    debug!(
-        pos_hg17,
-        pos_hg18, pos_hg19, pos_hg38, "Checking position columns"
+fn dump_legend(ctx: &Ctx, row: &[String]) -> Result<(), PipelineError> {
+    println!("trait_name\t{}", ctx.trait_name);
+    for (col, val) in ctx.sheet.header.iter().zip(row) {
+        println!("{}\t{}", col, val);
+    }
+    let delim = match parse_delim(ctx.sheet.get_from_row(row, "column_delim"))? {
+        '\t' => "tab",
+        ',' => "comma",
+        _ => "space",
+    };
+    println!("[resolved] delimiter\t{}", delim);
+    println!(
+        "[resolved] build\t{}",
+        ctx.sheet.get_from_row(row, "hg_version")
     );
-    if pos_hg17 || pos_hg18 || pos_hg19 || pos_hg38 {
-        let chr_idx = raw_data.idx(if pos_hg17 {
-            "chr_hg17"
-        } else if pos_hg18 {
-            "chr_hg18"
-        } else if pos_hg19 {
-            "chr_hg19"
-        } else {
-            "chr_hg38"
-        });
-        let pos_idx = raw_data.idx(if pos_hg17 {
-            "pos_hg17"
-        } else if pos_hg18 {
-            "pos_hg18"
-        } else if pos_hg19 {
-            "pos_hg19"
+    println!(
+        "[resolved] effect_scale\t{}",
+        if ctx.sheet.get_from_row(row, "effect_is_OR") == "Y" {
+            "odds ratio, converted to log odds"
         } else {
-            "pos_hg38"
-        });
-        for (i, r) in raw_data.data.iter().enumerate() {
-            writeln!(
-                bed,
-                "chr{}\t{}\t{}\t{}",
-                r[chr_idx],
-                r[pos_idx].parse::<i64>().unwrap() - 1,
-                r[pos_idx],
-                i + 2
-            )
-            .unwrap();
+            "beta"
         }
-        drop(bed);
-        if pos_hg17 || pos_hg18 {
-            std::process::Command::new(&ctx.args.liftover)
-                .arg(current_dir.join("input.bed"))
-                .arg(liftover_dir.join(if pos_hg17 {
-                    "hg17ToHg19.over.chain.gz"
-                } else {
-                    "hg18ToHg19.over.chain.gz"
-                }))
-                .arg(current_dir.join("input2.bed"))
-                .arg(current_dir.join("1unlifted.bed"))
-                .status()
-                .unwrap();
-            let mut hg19 = std::fs::File::create(current_dir.join("hg19.bed")).unwrap();
-            for line in std::fs::read_to_string(current_dir.join("input2.bed"))
-                .unwrap()
-                .lines()
-            {
-                writeln!(hg19, "{}", line.strip_prefix("chr").unwrap_or(line)).unwrap();
-            }
-        } else {
-            std::fs::rename(
-                current_dir.join("input.bed"),
-                current_dir.join("input2.bed"),
-            )
-            .unwrap();
-        }
-        std::process::Command::new(&ctx.args.liftover)
-            .arg(current_dir.join("input2.bed"))
-            .arg(liftover_dir.join(if pos_hg38 {
-                "hg38ToHg19.over.chain.gz"
-            } else {
-                "hg19ToHg38.over.chain.gz"
-            }))
-            .arg(current_dir.join("final.bed"))
-            .arg(current_dir.join("unlifted.bed"))
-            .status()
-            .unwrap();
-        let hg38_input = if pos_hg38 { "input2.bed" } else { "final.bed" };
-        debug!(hg38_input, "Reading hg38 bed file");
-        let mut hg38 = std::fs::File::create(current_dir.join("hg38.bed")).unwrap();
-        for line in std::fs::read_to_string(current_dir.join(hg38_input))
-            .unwrap()
-            .lines()
-        {
-            writeln!(hg38, "{}", line.strip_prefix("chr").unwrap_or(line)).unwrap();
-        }
-        std::fs::remove_file(current_dir.join(hg38_input)).unwrap();
-        if pos_hg19 || pos_hg38 {
-            let hg19_input = if pos_hg38 { "final.bed" } else { "input2.bed" };
-            debug!(hg19_input, "Reading hg19 bed file");
-            let mut hg19 = std::fs::File::create(current_dir.join("hg19.bed")).unwrap();
-            for line in std::fs::read_to_string(current_dir.join(hg19_input))
-                .unwrap()
-                .lines()
-            {
-                writeln!(hg19, "{}", line.strip_prefix("chr").unwrap_or(line)).unwrap();
-            }
-            std::fs::remove_file(current_dir.join(hg19_input)).unwrap();
+    );
+    for col in ASSIGN_COL_NAMES.iter() {
+        println!("[resolved] {}\t{}", col, ctx.sheet.get_from_row(row, col));
+    }
+    Ok(())
+}
+
+fn effect_type(ctx: &Ctx, row: &[String]) -> Result<EffectType, PipelineError> {
+    if let Some(effect_type) = ctx.args.effect_type {
+        return Ok(effect_type);
+    }
+    if ctx.sheet.idx_opt("effect_type").is_some() {
+        let effect_type = ctx.sheet.get_from_row(row, "effect_type");
+        if effect_type != "NA" {
+            let Ok(effect_type) = <EffectType as clap::ValueEnum>::from_str(effect_type, true)
+            else {
+                return Err(PipelineError::InvalidEffectType(effect_type.to_string()));
+            };
+            return Ok(effect_type);
         }
+    }
+    if ctx.sheet.get_from_row(row, "effect_is_OR") == "Y" {
+        Ok(EffectType::Or)
     } else {
-        error!("No position columns found in the raw data file");
-        panic!();
+        Ok(EffectType::Beta)
     }
 }
 
-#[tracing::instrument(skip(ctx, raw_data))]
-fn dbsnp_matching(ctx: &Ctx, mut raw_data: Data) -> (Data, Data) {
-    debug!("Reading hg19 and hg38 bed files");
-    let hg19 = {
-        if raw_data.header.contains(&"chr_hg19".to_string()) {
-            None
-        } else {
-            raw_data.header.push("chr_hg19".to_string());
-            raw_data.header.push("pos_hg19".to_string());
-            let file =
-                std::fs::File::open(std::env::current_dir().unwrap().join("hg19.bed")).unwrap();
-            Some(
-                Data::read('\t', file, false)
-                    .data
-                    .into_iter()
-                    .map(|x| (x.get(3).unwrap().parse::<usize>().unwrap() - 2, x))
-                    .collect::<HashMap<usize, _>>(),
-            )
-        }
-    };
-    let hg38 = {
-        if raw_data.header.contains(&"chr_hg38".to_string()) {
-            None
-        } else {
-            raw_data.header.push("chr_hg38".to_string());
-            raw_data.header.push("pos_hg38".to_string());
-            let file =
-                std::fs::File::open(std::env::current_dir().unwrap().join("hg38.bed")).unwrap();
-            Some(
-                Data::read('\t', file, false)
-                    .data
-                    .into_iter()
-                    .map(|x| (x.get(3).unwrap().parse::<usize>().unwrap() - 2, x))
-                    .collect::<HashMap<usize, _>>(),
-            )
+/// Complementary error function, accurate to about 1e-7 (Numerical Recipes'
+/// erfcc)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let ans = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        ans
+    } else {
+        2.0 - ans
+    }
+}
+
+/// Fills missing p-values from the z-scores in the effect size column
+fn z_pvalues(ctx: &Ctx, raw_data: &mut Data) {
+    let effect_size = raw_data.idx("effect_size");
+    let pvalue = raw_data.idx_or_push("pvalue");
+    let derived = raw_data
+        .data
+        .par_iter_mut()
+        .filter(|r| r[pvalue] == "NA")
+        .filter_map(|r| {
+            let z = r[effect_size].parse::<f64>().ok()?;
+            r[pvalue] = ctx.fmt_f64(erfc(z.abs() / std::f64::consts::SQRT_2));
+            Some(())
+        })
+        .count();
+    info!(derived, "Derived p-values from z-scores");
+    ctx.report.set("pvalue_from_z", derived);
+}
+
+/// Fills missing p-values, in order of preference, from the Wald statistic
+/// effect_size / standard_error or, for z-score studies, the z-score itself,
+/// recording which was used (or `reported`) in `pvalue_source`
+fn impute_pvalues(ctx: &Ctx, data: &mut Data, effect_type: EffectType) {
+    let effect_size = data.idx("effect_size");
+    let standard_error = data.idx("standard_error");
+    let pvalue = data.idx("pvalue");
+    let pvalue_source = data.idx_or_push("pvalue_source");
+    data.data.par_iter_mut().for_each(|r| {
+        if r[pvalue].parse::<f64>().is_ok_and(|x| !x.is_nan()) {
+            r[pvalue_source] = "reported".to_string();
+            return;
         }
+        let beta = r[effect_size].parse::<f64>().ok().filter(|x| x.is_finite());
+        let se = r[standard_error]
+            .parse::<f64>()
+            .ok()
+            .filter(|x| x.is_finite() && *x > 0.0);
+        let (z, source) = match (beta, se) {
+            (Some(beta), Some(se)) => (beta / se, "beta_se"),
+            (Some(z), None) if effect_type == EffectType::Z => (z, "z"),
+            _ => {
+                r[pvalue_source] = "NA".to_string();
+                return;
+            },
+        };
+        r[pvalue] = ctx.fmt_f64(erfc(z.abs() / std::f64::consts::SQRT_2));
+        r[pvalue_source] = source.to_string();
+    });
+    let count = |source: &str| {
+        data.data
+            .iter()
+            .filter(|r| r[pvalue_source] == source)
+            .count()
     };
-    debug!(
-        raw_data = raw_data.data.len(),
-        "Read hg19 and hg38 bed files"
-    );
-    let header_len = raw_data.header.len();
-    raw_data
+    let (beta_se, z, missing) = (count("beta_se"), count("z"), count("NA"));
+    info!(beta_se, z, missing, "Imputed missing p-values");
+    ctx.report.set_in("pvalue_imputation", "beta_se", beta_se);
+    ctx.report.set_in("pvalue_imputation", "z", z);
+    ctx.report.set_in("pvalue_imputation", "missing", missing);
+}
+
+/// Replaces z-scores with effect sizes and standard errors on the standardized
+/// scale, using se = 1 / sqrt(2 * EAF * (1 - EAF) * (N + z^2))
+fn reconstruct_z_effects(ctx: &Ctx, raw_data: &mut Data) {
+    let effect_size = raw_data.idx("effect_size");
+    let standard_error = raw_data.idx_or_push("standard_error");
+    let eaf = raw_data.idx_or_push("EAF");
+    let n_total = raw_data.idx("N_total");
+    let reconstructed = raw_data
         .data
         .par_iter_mut()
-        .enumerate()
-        .for_each(move |(i, r)| {
-            reserve_to(r, header_len);
-            if let Some(ref hg19) = hg19 {
-                let hg19 = hg19.get(&i);
-                if let Some(hg19) = hg19 {
-                    r.push(hg19.first().unwrap().to_string());
-                    r.push(hg19.get(2).unwrap().to_string());
-                } else {
-                    r.push("NA".to_string());
-                    r.push("NA".to_string());
-                }
-            }
-            if let Some(ref hg38) = hg38 {
-                let hg38 = hg38.get(&i);
-                if let Some(hg38) = hg38 {
-                    r.push(hg38.first().unwrap().to_string());
-                    r.push(hg38.get(2).unwrap().to_string());
-                } else {
-                    r.push("NA".to_string());
-                    r.push("NA".to_string());
-                }
+        .map(|r| {
+            let z = r[effect_size].parse::<f64>();
+            let eaf = r[eaf].parse::<f64>();
+            let n = r[n_total].parse::<f64>();
+            let (Ok(z), Ok(eaf), Ok(n)) = (z, eaf, n) else {
+                r[effect_size] = "NA".to_string();
+                r[standard_error] = "NA".to_string();
+                return 0;
+            };
+            let se = 1.0 / (2.0 * eaf * (1.0 - eaf) * (n + z * z)).sqrt();
+            if !se.is_finite() {
+                r[effect_size] = "NA".to_string();
+                r[standard_error] = "NA".to_string();
+                return 0;
             }
-        });
-
-    debug!("Reordering columns");
-    raw_data.reorder(&[
-        "chr_hg19",
-        "pos_hg19",
-        "ref",
-        "alt",
-        "effect_size",
-        "standard_error",
-        "EAF",
-        "pvalue",
-        "pvalue_het",
-        "N_total",
-        "N_case",
-        "N_ctrl",
-        "chr_hg38",
-        "pos_hg38",
-    ]);
-    // raw_data.write("dbsnp.e.txt.gz");
-    debug!(len = raw_data.data.len(), "Raw data after bed matching");
+            r[effect_size] = ctx.fmt_f64(z * se);
+            r[standard_error] = ctx.fmt_f64(se);
+            1
+        })
+        .sum::<usize>();
+    let missing = raw_data.data.len() - reconstructed;
+    warn!(
+        reconstructed,
+        missing, "Reconstructed effect sizes and standard errors from z-scores"
+    );
+    ctx.report
+        .set_in("effect_reconstruction", "reconstructed", reconstructed);
+    ctx.report
+        .set_in("effect_reconstruction", "missing", missing);
+}
 
-    debug!("Reading dbSNP file");
-    let dbsnp = flate2::read::GzDecoder::new(std::fs::File::open(&ctx.args.dbsnp_file).unwrap());
-    let dbsnp = Data::read('\t', dbsnp, true);
-    debug!("Merging dbSNP data");
-    let dbsnp_idxs = [
-        dbsnp.idx("chr"),
-        dbsnp.idx("pos_hg19"),
-        dbsnp.idx("ref"),
-        dbsnp.idx("alt"),
-        dbsnp.idx("pos_hg38"),
-    ];
-    debug!("Creating dbsnp map");
-    let dbsnp_map: HashMap<(&str, &str, &str, &str, &str), &Vec<String>> =
-        HashMap::from_par_iter(dbsnp.data.par_iter().map(|x| {
-            (
-                (
-                    x[dbsnp_idxs[0]].as_str(),
-                    x[dbsnp_idxs[1]].as_str(),
-                    x[dbsnp_idxs[2]].as_str(),
-                    x[dbsnp_idxs[3]].as_str(),
-                    x[dbsnp_idxs[4]].as_str(),
-                ),
-                x,
-            )
-        }));
-    debug!("Getting raw data indexes");
-    let raw_data_idxs = [
-        raw_data.idx("chr_hg19"),
-        raw_data.idx("pos_hg19"),
-        raw_data.idx("ref"),
-        raw_data.idx("alt"),
-        raw_data.idx("pos_hg38"),
-    ];
-    let raw_data_merged_flipped_idxs = [
-        raw_data.idx("chr_hg19"),
-        raw_data.idx("pos_hg19"),
-        raw_data.idx("alt"),
-        raw_data.idx("ref"),
-        raw_data.idx("pos_hg38"),
-    ];
-    let mut raw_data_merged = raw_data.clone();
-    let raw_data_merged_data = std::mem::take(&mut raw_data_merged.data);
-    for i in 0..dbsnp.header.len() {
-        if !dbsnp_idxs.contains(&i) {
-            debug!(i, header = dbsnp.header[i], "Adding missing column");
-            raw_data_merged.header.push(dbsnp.header[i].clone());
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            cur[j + 1] = (prev[j] + usize::from(ca != *cb))
+                .min(prev[j + 1] + 1)
+                .min(cur[j] + 1);
         }
+        prev = cur;
     }
-    raw_data_merged.header.push("unique_id".to_string());
-    let unique_id_idx = raw_data_merged.idx("unique_id");
-    let mut raw_data_flipped = raw_data_merged.clone();
-    debug!(header = ?raw_data_merged.header, "Header");
-    debug!(idxs = ?raw_data_idxs, "Raw data indexes");
-    let header_len = raw_data_merged.header.len();
-    raw_data_merged.data = raw_data_merged_data
-        .into_par_iter()
-        .filter_map(|mut r| {
-            reserve_to(&mut r, header_len);
-            let key = (
-                r[raw_data_idxs[0]].as_str(),
-                r[raw_data_idxs[1]].as_str(),
-                r[raw_data_idxs[2]].as_str(),
-                r[raw_data_idxs[3]].as_str(),
-                r[raw_data_idxs[4]].as_str(),
-            );
-            let dbsnp_data = *dbsnp_map.get(&key)?;
-            (0..dbsnp.header.len()).for_each(|i| {
-                if !dbsnp_idxs.contains(&i) {
-                    r.push(dbsnp_data[i].clone());
-                }
-            });
-            r.push(format!(
-                "{}_{}_{}_{}",
-                r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]],
-            ));
-            Some(r)
-        })
-        .collect::<Vec<_>>();
-    debug!("Flipping alleles");
-    let mut raw_data_flipped_data = std::mem::take(&mut raw_data_flipped.data);
-    let header_len = raw_data_flipped.header.len();
-    raw_data_flipped_data = raw_data_flipped_data
-        .into_par_iter()
-        .filter_map(|mut r| {
-            reserve_to(&mut r, header_len);
-            let key = (
-                r[raw_data_merged_flipped_idxs[0]].as_str(),
-                r[raw_data_merged_flipped_idxs[1]].as_str(),
-                r[raw_data_merged_flipped_idxs[2]].as_str(),
-                r[raw_data_merged_flipped_idxs[3]].as_str(),
-                r[raw_data_merged_flipped_idxs[4]].as_str(),
-            );
-            let dbsnp_data = *dbsnp_map.get(&key)?;
-            (0..dbsnp.header.len()).for_each(|i| {
-                if !dbsnp_idxs.contains(&i) {
-                    r.push(dbsnp_data[i].clone());
-                }
+    prev[b.len()]
+}
+
+/// Finds canonical columns that appear more than once after the legend renames,
+/// e.g. a raw `N_total` column next to the one the legend maps to `N_total`.
+/// Renames keep column positions, so the legend's column is the one at the
+/// position of its raw name; the others get a `_raw` suffix.
+fn resolve_header_collisions(
+    ctx: &Ctx,
+    row: &[String],
+    raw_header: &[String],
+    header: &mut [String],
+) -> Result<(), PipelineError> {
+    let mut collisions = vec![];
+    for (col, legend_col) in ASSIGN_COL_NAMES
+        .iter()
+        .map(|x| (x.trim_end_matches("_column"), *x))
+    {
+        let positions = header
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| *x == col)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if positions.len() < 2 {
+            continue;
+        }
+        let val = ctx.sheet.get_from_row(row, legend_col);
+        if ctx.args.strict_header {
+            return Err(PipelineError::HeaderCollision {
+                column: col.to_string(),
+                legend: val.clone(),
             });
-            r.push(format!(
-                "{}_{}_{}_{}",
-                r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]],
-            ));
-            Some(r)
-        })
-        .collect::<Vec<_>>();
-    debug!("Merging flipped alleles");
-    let unique_ids: HashSet<&str> = HashSet::from_iter(
-        raw_data_merged
-            .data
+        }
+        let keep = raw_header
             .iter()
-            .map(|x| x[unique_id_idx].as_str()),
-    );
-    raw_data_flipped.data = raw_data_flipped_data
-        .into_par_iter()
-        .filter(|x| !unique_ids.contains(x[unique_id_idx].as_str()))
-        .collect::<Vec<_>>();
-    let alt = raw_data_flipped.idx("alt");
-    let ref_ = raw_data_flipped.idx("ref");
-    let effect_size = raw_data_flipped.idx("effect_size");
-    let eaf = raw_data_flipped.idx("EAF");
-    raw_data_flipped.data.par_iter_mut().for_each(|r| {
-        let (one, two) = r.split_at_mut(alt.max(ref_));
-        let min = alt.min(ref_);
-        let max = alt.max(ref_);
-        std::mem::swap(&mut one[min], &mut two[max]);
-        let es = r[effect_size].parse::<f64>().unwrap();
-        r[effect_size] = (-es).to_string();
-        let e = r[eaf].parse::<f64>().unwrap();
-        r[eaf] = (1.0 - e).to_string();
-        let unique_id = r.len() - 1;
-        r[unique_id] = format!(
-            "{}_{}_{}_{}",
-            r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]]
+            .position(|x| x == val)
+            .filter(|x| positions.contains(x))
+            .unwrap_or(positions[0]);
+        warn!(
+            col,
+            legend = val,
+            "Column appears more than once after renaming, keeping the legend's column"
         );
-    });
-    raw_data_merged.data.extend(raw_data_flipped.data);
-    let mut seen = HashSet::new();
-    raw_data_merged
-        .data
-        .retain(|x| seen.insert(x[unique_id_idx].as_str().to_string()));
-    debug!("Merging missing data");
-    let new_order = [
-        "rsid",
-        "unique_id",
-        "chr_hg19",
-        "pos_hg19",
-        "ref",
-        "alt",
-        "effect_size",
-        "standard_error",
-        "EAF",
-        "pvalue",
-        "pvalue_het",
-        "N_total",
-        "N_case",
-        "N_ctrl",
-        "chr_hg38",
-        "pos_hg38",
-        "gnomAD_AF_EUR",
-        "gnomAD_AF_AMR",
-        "gnomAD_AF_AFR",
-        "gnomAD_AF_EAS",
-        "gnomAD_AF_SAS",
-    ];
-    debug!("Constructing raw unique ids");
-    let raw_unique_ids: HashSet<(&str, &str, &str, &str)> = HashSet::from_par_iter(
-        raw_data_merged
-            .data
-            .par_iter()
-            .map(|r| {
-                (
-                    r[raw_data_idxs[0]].as_str(),
-                    r[raw_data_idxs[1]].as_str(),
-                    r[raw_data_idxs[2]].as_str(),
-                    r[raw_data_idxs[3]].as_str(),
-                )
+        for i in positions.into_iter().filter(|i| *i != keep) {
+            header[i] = format!("{}_raw", col);
+        }
+        collisions.push(col);
+    }
+    ctx.report.set("header_collisions", collisions);
+    Ok(())
+}
+
+/// Fails up front if a column named in the legend isn't in the raw header,
+/// suggesting the closest raw column for each
+fn check_legend_columns(
+    ctx: &Ctx,
+    row: &[String],
+    raw_header: &[String],
+) -> Result<(), PipelineError> {
+    let mismatches = ASSIGN_COL_NAMES
+        .iter()
+        .filter_map(|col| {
+            let val = ctx.sheet.get_from_row(row, col);
+            if val == "NA" || raw_header.contains(val) {
+                return None;
+            }
+            let suggestion = raw_header
+                .iter()
+                .map(|h| {
+                    let d = if h.eq_ignore_ascii_case(val) {
+                        0
+                    } else {
+                        edit_distance(&h.to_lowercase(), &val.to_lowercase())
+                    };
+                    (d, h)
+                })
+                .filter(|(d, _)| *d <= 2)
+                .min_by_key(|(d, _)| *d)
+                .map(|(_, h)| h.as_str());
+            Some(match suggestion {
+                Some(h) => format!("{}={} (did you mean {}?)", col, val, h),
+                None => format!("{}={}", col, val),
             })
-            .chain(raw_data_merged.data.par_iter().map(|r| {
-                (
-                    r[raw_data_idxs[0]].as_str(),
-                    r[raw_data_idxs[1]].as_str(),
-                    r[raw_data_idxs[3]].as_str(),
-                    r[raw_data_idxs[2]].as_str(),
-                )
-            })),
-    );
-    let pos_hg19 = raw_data.idx("pos_hg19");
-    let pos_hg38 = raw_data.idx("pos_hg38");
-    debug!("Constructing missing data");
-    let header = raw_data.header.clone();
-    let raw_data_missing = raw_data
-        .data
-        .into_par_iter()
-        .filter(|r| {
-            !raw_unique_ids.contains(&(
-                r[raw_data_idxs[0]].as_str(),
-                r[raw_data_idxs[1]].as_str(),
-                r[raw_data_idxs[2]].as_str(),
-                r[raw_data_idxs[3]].as_str(),
-            )) && r[pos_hg19] != "NA"
-                && r[pos_hg38] != "NA"
-                && r[pos_hg19] != "NaN"
-                && r[pos_hg38] != "NaN"
         })
         .collect::<Vec<_>>();
-    let mut raw_data_missing = Data {
-        header,
-        data: raw_data_missing,
+    if !mismatches.is_empty() {
+        return Err(PipelineError::LegendColumnsMissing {
+            mismatches,
+            header: raw_header.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+fn check_pvalue_scale(ctx: &Ctx, raw_data: &mut Data, pvalue: usize) {
+    let pvalues = raw_data
+        .data
+        .iter()
+        .filter_map(|r| r[pvalue].parse::<f64>().ok())
+        .filter(|x| !x.is_nan());
+    let (mut above_one, mut negative, mut in_unit) = (0, 0, 0);
+    for p in pvalues {
+        if p > 1.0 {
+            above_one += 1;
+        } else if p < 0.0 {
+            negative += 1;
+        } else if p > 0.0 {
+            in_unit += 1;
+        }
+    }
+    // p-values above 1 are most likely -log10(p), and p-values that are all
+    // non-positive are most likely ln(p)
+    let scale = if above_one > 0 && negative == 0 {
+        "neg_log10"
+    } else if negative > 0 && above_one == 0 && in_unit == 0 {
+        "ln"
+    } else {
+        "linear"
     };
-    debug!(
-        header = ?raw_data.header,
-        len = raw_data.header.len(),
-        "Raw data header"
-    );
-    debug!(
-        header = ?raw_data_merged.header,
-        len = raw_data_merged.header.len(),
-        "Merged data header"
-    );
-    debug!(
-        header = ?raw_data_missing.header,
-        len = raw_data_missing.header.len(),
-        "Missing data header"
+    ctx.report.set_in("pvalue_scale", "detected", scale);
+    ctx.report.set_in("pvalue_scale", "above_one", above_one);
+    ctx.report.set_in("pvalue_scale", "negative", negative);
+    if above_one == 0 && negative == 0 {
+        ctx.report.set_in("pvalue_scale", "converted", false);
+        return;
+    }
+    warn!(
+        above_one,
+        negative,
+        scale,
+        "The pvalue column contains values outside [0, 1] and may be on a log scale"
     );
-    debug!("Reordering columns");
-    raw_data_merged.reorder(&new_order);
-    for i in 0..dbsnp.header.len() {
-        if !dbsnp_idxs.contains(&i) {
-            debug!(i, header = dbsnp.header[i], "Adding missing column");
-            raw_data_missing.header.push(dbsnp.header[i].clone());
-        }
+    let convert = ctx.args.assume_logp && scale != "linear";
+    ctx.report.set_in("pvalue_scale", "converted", convert);
+    if !convert {
+        return;
     }
-    raw_data_missing.header.push("unique_id".to_string());
-    let header_len = raw_data_missing.header.len();
-    raw_data_missing.data.par_iter_mut().for_each(|r| {
-        reserve_to(r, header_len);
-        for i in 0..dbsnp.header.len() {
-            if !dbsnp_idxs.contains(&i) {
-                r.push("NA".to_string());
-            }
+    info!(scale, "Converting p-values from a log scale");
+    raw_data.data.par_iter_mut().for_each(|r| {
+        if let Ok(p) = r[pvalue].parse::<f64>() {
+            let p = if scale == "ln" {
+                p.exp()
+            } else {
+                10f64.powf(-p)
+            };
+            r[pvalue] = ctx.fmt_f64(p);
         }
-        r.push(format!(
-            "{}_{}_{}_{}",
-            r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]]
-        ));
     });
-    debug!(header = ?raw_data_missing.header);
-    assert_eq!(
-        raw_data_missing.header.len(),
-        raw_data_missing.data[0].len()
-    );
-    raw_data_missing.reorder(&new_order);
-    debug!(header = ?raw_data_merged.header);
+}
 
-    assert_eq!(raw_data_merged.header.len(), raw_data_merged.data[0].len());
+/// Appends the --keep-columns to a reorder list, skipping any the list
+/// already has.
+fn push_keep_columns<'a>(ctx: &'a Ctx, order: &mut Vec<&'a str>) {
+    for col in &ctx.args.keep_columns {
+        if !order.contains(&col.as_str()) {
+            order.push(col);
+        }
+    }
+}
+
+#[tracing::instrument(skip(ctx))]
+fn preformat(ctx: &Ctx) -> Result<Data, PipelineError> {
+    let row = legend_row(ctx)?;
+    let file_path = ctx.sheet.get_from_row(row, "file_path").as_str();
+    let raw_input_file = match ctx.args.raw_path_base {
+        RawPathBase::RawDir => {
+            let Some(raw_input_dir) = &ctx.args.raw_input_dir else {
+                return Err(PipelineError::RawInputDirRequired);
+            };
+            let raw_input_dir = std::path::Path::new(raw_input_dir);
+            if !raw_input_dir.exists() {
+                return Err(PipelineError::RawInputDirMissing(raw_input_dir.into()));
+            }
+            if !raw_input_dir.is_dir() {
+                return Err(PipelineError::RawInputDirNotDir(raw_input_dir.into()));
+            }
+            raw_input_dir.join(file_path.strip_prefix('/').unwrap_or(file_path))
+        },
+        RawPathBase::Cwd => std::env::current_dir()?.join(file_path),
+        RawPathBase::Absolute => {
+            let file_path = std::path::Path::new(file_path);
+            if !file_path.is_absolute() {
+                return Err(PipelineError::NotAbsolute(file_path.into()));
+            }
+            file_path.to_path_buf()
+        },
+    };
+    if !raw_input_file.exists() {
+        return Err(PipelineError::RawInputMissing(raw_input_file));
+    }
+    if !raw_input_file.is_file() {
+        return Err(PipelineError::RawInputNotFile(raw_input_file));
+    }
+    info!(raw_input_file = %raw_input_file.to_string_lossy(), "Reading raw input file");
+    let delim = parse_delim(ctx.sheet.get_from_row(row, "column_delim"))?;
+    let mut file = std::fs::File::open(&raw_input_file)?;
+    // upstream files are often gzipped without a .gz extension, so go by the
+    // magic bytes
+    let mut magic = [0; 2];
+    let gz = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    file.seek(std::io::SeekFrom::Start(0))?;
+    debug!(gz, "Detected raw input compression");
+    // by default the whole file is read into a string and then split into
+    // rows, so peak memory is roughly twice the decompressed file; with
+    // --low-memory it is streamed and only the legend's columns are kept
+    let mut cols = ASSIGN_COL_NAMES
+        .iter()
+        .map(|x| ctx.sheet.get_from_row(row, x).as_str())
+        .filter(|x| *x != "NA")
+        .collect::<Vec<_>>();
+    if let Some(x_col) = &ctx.args.x_sample_size_column {
+        cols.push(x_col);
+    }
+    cols.extend(ctx.args.keep_columns.iter().map(|x| x.as_str()));
+    cols.sort();
+    cols.dedup();
+    let read_raw_data = |delim: char, file: Box<dyn std::io::Read + '_>| {
+        if ctx.args.low_memory {
+            Data::read_lines_projected(delim, file, &cols)
+        } else {
+            read_raw_data(delim, file)
+        }
+    };
+    let mut raw_data = if let Some(pre_reader) = &ctx.args.pre_reader {
+        info!(pre_reader, "Converting raw input file with pre-reader");
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", pre_reader))
+            .arg("sh")
+            .arg(&raw_input_file)
+            .output()?;
+        if !output.status.success() {
+            return Err(PipelineError::PreReaderFailed {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        if !output.stderr.is_empty() {
+            warn!(stderr = %String::from_utf8_lossy(&output.stderr), "Pre-reader wrote to stderr");
+        }
+        read_raw_data('\t', Box::new(output.stdout.as_slice()))
+    } else if gz {
+        let gz = flate2::read::GzDecoder::new(file);
+        read_raw_data(delim, Box::new(gz))
+    } else {
+        read_raw_data(delim, Box::new(file))
+    };
+    debug!(header = ?raw_data.header, "Header");
+    if raw_data.data.is_empty() {
+        if !ctx.args.allow_empty {
+            return Err(PipelineError::EmptyInput(raw_input_file));
+        }
+        warn!(file = %raw_input_file.to_string_lossy(), "Raw data file has no data rows");
+        return Ok(raw_data);
+    }
+    let raw_header = raw_data.header.clone();
+    check_legend_columns(ctx, row, &raw_header)?;
+    let mut mapped = serde_json::Map::new();
+    let mut unmapped = vec![];
+    for col in ASSIGN_COL_NAMES.iter() {
+        let val = ctx.sheet.get_from_row(row, col);
+        if val != "NA" && raw_data.header.iter().any(|x| x == val) {
+            mapped.insert(val.clone(), col.to_string().into());
+        } else {
+            unmapped.push(*col);
+        }
+        if val != "NA" {
+            raw_data.rename(val, col);
+        }
+    }
+    let dropped = raw_header
+        .iter()
+        .filter(|x| !mapped.contains_key(*x))
+        .map(|x| x.as_str())
+        .collect::<Vec<_>>();
+    debug!(?mapped, ?dropped, ?unmapped, "Raw columns");
+    if ctx.args.columns_report {
+        info!(?mapped, ?dropped, ?unmapped, "Raw columns");
+        ctx.report.set_in("columns", "mapped", mapped);
+        ctx.report.set_in("columns", "dropped", dropped);
+        ctx.report.set_in("columns", "unmapped", unmapped);
+    }
+    debug!(header = ?raw_data.header, "Header");
+    for chr in raw_data.col_mut("chr") {
+        // a) Remove "chr" prefix
+        // b) Convert 23-25 to X, Y, M
+        if let Some(c) = normalize_chr(chr) {
+            *chr = c.to_string();
+        }
+    }
+    normalize_rsids(ctx, &mut raw_data, "raw");
+    // c) Change alleles to uppercase
+    for r in raw_data.col_mut("ref") {
+        *r = r.to_ascii_uppercase();
+    }
+    for a in raw_data.col_mut("alt") {
+        *a = a.to_ascii_uppercase();
+    }
+    // Validate positions up front so that liftover can rely on them. A kept
+    // position column for the study's other build lets liftover be skipped, so
+    // it is validated the same way.
+    let other_pos = match ctx.sheet.get_from_row(row, "hg_version").as_str() {
+        "hg19" => Some("pos_hg38"),
+        "hg38" => Some("pos_hg19"),
+        _ => None,
+    }
+    .filter(|x| ctx.args.keep_columns.iter().any(|k| k == x));
+    let pos_cols = std::iter::once("pos")
+        .chain(other_pos.filter(|x| raw_data.idx_opt(x).is_some()))
+        .map(|x| (x, raw_data.idx(x)))
+        .collect::<Vec<_>>();
+    if ctx.args.repair_positions {
+        let repair = |pos: &mut String| {
+            if pos.parse::<u64>().is_ok() {
+                return false;
+            }
+            match pos.trim().parse::<f64>() {
+                Ok(p) if p.fract() == 0.0 && p >= 1.0 && p <= u64::MAX as f64 => {
+                    *pos = (p as u64).to_string();
+                    true
+                },
+                _ => false,
+            }
+        };
+        let repaired = raw_data
+            .data
+            .par_iter_mut()
+            .map(|r| {
+                pos_cols
+                    .iter()
+                    .filter(|(_, pos)| repair(&mut r[*pos]))
+                    .count()
+            })
+            .sum::<usize>();
+        info!(repaired, "Repaired positions");
+        ctx.report.set("repaired_positions", repaired);
+    }
+    let invalid_pos = |r: &[String]| {
+        pos_cols
+            .iter()
+            .find(|(_, pos)| !r[*pos].parse::<u64>().is_ok_and(|p| p > 0))
+            .copied()
+    };
+    let data = std::mem::take(&mut raw_data.data);
+    let (valid, invalid): (Vec<_>, Vec<_>) =
+        data.into_par_iter().partition(|r| invalid_pos(r).is_none());
+    raw_data.data = valid;
+    let parse_errors = ParseErrors::new(&raw_data, &pos_cols);
+    for r in &invalid {
+        if let Some((col, _)) = invalid_pos(r) {
+            parse_errors.push(r, col);
+        }
+    }
+    if !invalid.is_empty() {
+        let examples = invalid
+            .iter()
+            .filter_map(|r| invalid_pos(r).map(|(_, pos)| r[pos].clone()))
+            .take(10)
+            .collect::<Vec<_>>();
+        warn!(
+            count = invalid.len(),
+            ?examples,
+            "Removed variants with invalid positions"
+        );
+        ctx.report.set(
+            "invalid_positions",
+            serde_json::json!({ "count": invalid.len(), "examples": examples }),
+        );
+    }
+    if ctx.args.study_dedup != StudyDedup::None {
+        dedup_study(ctx, &mut raw_data)?;
+    }
+    debug!(len = raw_data.data.len(), "Raw data before d and e");
+    let data = std::mem::take(&mut raw_data.data);
+    let keep_na_effect = ctx.args.na_effect == NaEffect::Keep;
+    raw_data.data = data
+        .into_par_iter()
+        .filter(|x| {
+            let r = raw_data.get_from_row(x.as_slice(), "ref");
+            let a = raw_data.get_from_row(x.as_slice(), "alt");
+            let effect_size = raw_data.get_from_row(x.as_slice(), "effect_size");
+            // debug!(?x, r, a, effect_size, "Checking ref, alt, and effect size");
+            // d) Remove SNPs with ambiguous ref or alt
+            r != "I"
+                && r != "D"
+                && r != "IND"
+                && r != "DEL"
+                && a != "I"
+                && a != "D"
+                && a != "IND"
+                && a != "DEL"
+            // e) Remove variants with nonsensical effect estimates
+                && (keep_na_effect || !is_na_effect(effect_size))
+        })
+        .collect::<Vec<_>>();
+    debug!(len = raw_data.data.len(), "Raw data after d and e");
+    if keep_na_effect {
+        let effect_size = raw_data.idx("effect_size");
+        let na_effects = raw_data
+            .data
+            .par_iter_mut()
+            .filter(|r| is_na_effect(&r[effect_size]))
+            .map(|r| r[effect_size] = "NA".to_string())
+            .count();
+        info!(na_effects, "Keeping variants with NA effect sizes");
+        ctx.report.set("na_effect_kept", na_effects);
+    }
+    // drop effect sizes that aren't numbers at all, such as `.` or `abc`
+    let effect_size = raw_data.idx("effect_size");
+    let data = std::mem::take(&mut raw_data.data);
+    let (valid, invalid): (Vec<_>, Vec<_>) = data
+        .into_par_iter()
+        .partition(|r| r[effect_size] == "NA" || r[effect_size].parse::<f64>().is_ok());
+    raw_data.data = valid;
+    for r in &invalid {
+        parse_errors.push(r, "effect_size");
+    }
+    if !invalid.is_empty() {
+        warn!(
+            count = invalid.len(),
+            "Removed variants with unparseable effect sizes"
+        );
+    }
+    ctx.report.set("invalid_effect_sizes", invalid.len());
+    // f) Convert OR to beta
+    let effect_type = effect_type(ctx, row)?;
+    ctx.report
+        .set("effect_type", format!("{:?}", effect_type).to_lowercase());
+    let effect_sizes = raw_data
+        .col("effect_size")
+        .map(|x| x.parse::<f64>().ok())
+        .collect::<Vec<_>>();
+    if effect_type == EffectType::Beta && effect_sizes.iter().flatten().all(|x| *x > 0.0) {
+        warn!(
+            "All effect sizes are positive yet effect_is_OR has been set to N. Please double \
+             check that effect estimates from the raw data file are indeed regression \
+             coefficients and not odds ratios"
+        );
+    }
+    if effect_type == EffectType::Or && effect_sizes.iter().flatten().any(|x| *x < 0.0) {
+        warn!(
+            "Some effect sizes are negative yet effect_is_OR has been set to Y. Please double \
+             check that effect estimates from the raw data file are indeed odds or hazard ratios \
+             and not regression coefficients"
+        );
+    }
+    // --impute-pvalue covers z-scores itself once the effects are final
+    if effect_type == EffectType::Z && !ctx.args.impute_pvalue {
+        z_pvalues(ctx, &mut raw_data);
+    }
+    if effect_type == EffectType::Or {
+        let data = std::mem::take(&mut raw_data.data);
+        raw_data.data = data
+            .into_par_iter()
+            .zip(effect_sizes)
+            .filter_map(|(mut r, e)| {
+                let Some(e) = e else {
+                    return Some(r);
+                };
+                let l = e.ln();
+                if l.is_nan() || l.is_infinite() {
+                    parse_errors.push(&r, "effect_size");
+                    None
+                } else {
+                    r[effect_size] = ctx.fmt_f64(l);
+                    Some(r)
+                }
+            })
+            .collect::<Vec<_>>();
+    }
+    debug!(len = raw_data.data.len(), "Raw data after f");
+    if let Some(standard_error) = raw_data.idx_opt("standard_error") {
+        let negative = raw_data
+            .data
+            .par_iter()
+            .filter(|r| r[standard_error].parse::<f64>().is_ok_and(|x| x < 0.0))
+            .count();
+        ctx.report.set("negative_standard_errors", negative);
+        if negative > 0 {
+            let msg = "Some standard errors are negative. Please check that the effect size and \
+                       standard error columns are not swapped";
+            if ctx.args.fail_on_warn {
+                return Err(PipelineError::NegativeStandardErrors(negative));
+            }
+            warn!(negative, "{}", msg);
+        }
+    }
+    if let Some(pvalue) = raw_data.idx_opt("pvalue") {
+        check_pvalue_scale(ctx, &mut raw_data, pvalue);
+    }
+    // g) Tabulate columns for sample sizes
+    for var in ["total", "case", "ctrl"] {
+        let var_col_name = ctx.sheet.get_from_row(row, &format!("N_{}_column", var));
+        if var_col_name != "NA" {
+            // rename column if values are present
+            raw_data.rename(&format!("N_{}_column", var), &format!("N_{}", var));
+        }
+    }
+    resolve_header_collisions(ctx, row, &raw_header, &mut raw_data.header)?;
+    let na = "NA".to_string();
+    // if no sample sizes indicated and gwas legend input is NA then set all three
+    // columns to NA
+    debug!("g: Adding header");
+    for var in ["total", "case", "ctrl"] {
+        if !raw_data.header.contains(&format!("N_{}", var)) {
+            raw_data.header.push(format!("N_{}", var));
+        }
+    }
+    debug!("g: Added header");
+    let header_len = raw_data.header.len();
+    raw_data.data.par_iter_mut().for_each(|r| {
+        let res = reserve_to(r, header_len);
+        for _ in 0..res {
+            r.push(na.clone());
+        }
+    });
+    debug!("g: Added NAs");
+    for var in ["total", "case", "ctrl"] {
+        let var_col_name = ctx.sheet.get_from_row(row, &format!("N_{}_column", var));
+        let var_value = ctx.sheet.get_from_row(row, &format!("N_{}", var));
+        if var_col_name == "NA" && var_value != "NA" {
+            // update column
+            for r in raw_data.col_mut(&format!("N_{}", var)) {
+                r.clone_from(var_value);
+            }
+        }
+    }
+    // set sample sizes that are not numeric to NA so they do not break the
+    // derivation below
+    for var in ["total", "case", "ctrl"] {
+        let col = format!("N_{}", var);
+        let idx = raw_data.idx(&col);
+        let invalid = raw_data
+            .data
+            .par_iter_mut()
+            .filter(|r| r[idx] != "NA" && !r[idx].parse::<f64>().is_ok_and(f64::is_finite))
+            .map(|r| r[idx] = "NA".to_string())
+            .count();
+        if invalid > 0 {
+            warn!(col, invalid, "Set non-numeric sample sizes to NA");
+        }
+        ctx.report.set_in("invalid_sample_sizes", &col, invalid);
+    }
+    // compile case control or total sample sizes if inoformation is available
+    let n_case = raw_data.idx("N_case");
+    let n_ctrl = raw_data.idx("N_ctrl");
+    let n_total = raw_data.idx("N_total");
+    raw_data.data.par_iter_mut().for_each(|r| {
+        let n = |x: &str| x.parse::<f64>().ok();
+        if let (Some(case), Some(ctrl)) = (n(&r[n_case]), n(&r[n_ctrl])) {
+            r[n_total] = ctx.fmt_f64(case + ctrl);
+        }
+        if let (Some(total), Some(ctrl), None) = (n(&r[n_total]), n(&r[n_ctrl]), n(&r[n_case])) {
+            r[n_case] = ctx.fmt_f64(total - ctrl);
+        }
+        if let (Some(total), Some(case), None) = (n(&r[n_total]), n(&r[n_case]), n(&r[n_ctrl])) {
+            r[n_ctrl] = ctx.fmt_f64(total - case);
+        }
+    });
+    if effect_type == EffectType::Z && ctx.args.reconstruct_effect {
+        reconstruct_z_effects(ctx, &mut raw_data);
+    }
+    if let Some(x_col) = &ctx.args.x_sample_size_column {
+        let Some(x_n) = raw_data.idx_opt(x_col) else {
+            return Err(PipelineError::XSampleSizeColumnMissing(x_col.clone()));
+        };
+        let chr = raw_data.idx("chr");
+        let overridden = raw_data
+            .data
+            .par_iter_mut()
+            .filter(|r| r[chr] == "X" && r[x_n].parse::<f64>().is_ok_and(f64::is_finite))
+            .map(|r| r[n_total] = r[x_n].clone())
+            .count();
+        info!(overridden, "Used X chromosome sample sizes");
+        ctx.report.set("x_sample_sizes", overridden);
+    }
+    if ctx.args.integer_n {
+        raw_data.data.par_iter_mut().for_each(|r| {
+            for idx in [n_case, n_ctrl, n_total] {
+                if let Ok(n) = r[idx].parse::<f64>() {
+                    if (n - n.round()).abs() < 1e-3 {
+                        r[idx] = format!("{}", n.round() as i64);
+                    }
+                }
+            }
+        });
+    }
+    debug!(len = raw_data.data.len(), "Raw data after g");
+    let mut order = vec![
+        "chr",
+        "pos",
+        "ref",
+        "alt",
+        "EAF",
+        "effect_size",
+        "standard_error",
+        "pvalue",
+        "pvalue_het",
+        "N_total",
+        "N_case",
+        "N_ctrl",
+    ];
+    // dbSNP matching needs the input rsid to validate coordinates against and
+    // to match on
+    if (ctx.args.coordinate_validation || ctx.args.match_by_rsid)
+        && raw_data.idx_opt("rsid").is_some()
+    {
+        order.push("rsid");
+    }
+    let missing_keep = ctx
+        .args
+        .keep_columns
+        .iter()
+        .filter(|x| raw_data.idx_opt(x).is_none() && other_pos != Some(x.as_str()))
+        .collect::<Vec<_>>();
+    if !missing_keep.is_empty() {
+        warn!(columns = ?missing_keep, "--keep-columns are not in the raw data file, filling with NA");
+    }
+    push_keep_columns(ctx, &mut order);
+    // an all-NA position column for the other build would make liftover skip
+    // lifting it
+    if let Some(other_pos) = other_pos.filter(|x| raw_data.idx_opt(x).is_none()) {
+        warn!(
+            column = other_pos,
+            "--keep-columns position column is not in the raw data file, lifting it over instead"
+        );
+        order.retain(|x| *x != other_pos);
+    }
+    raw_data.reorder(&order);
+    if ctx.args.keep_failed_parse_rows {
+        parse_errors.write(ctx);
+    }
+    let hg_version = ctx.sheet.get_from_row(row, "hg_version");
+    raw_data.rename("pos", &format!("pos_{}", hg_version));
+    raw_data.rename("chr", &format!("chr_{}", hg_version));
+    debug!(header = ?raw_data.header, "Header");
+    if let Some(r) = raw_data.data.first() {
+        assert_eq!(raw_data.header.len(), r.len());
+    } else if !ctx.args.allow_empty {
+        return Err(PipelineError::NoRowsLeft(raw_input_file));
+    }
+    Ok(raw_data)
+}
+
+/// One aligned block of a UCSC chain, mapping the source interval it is
+/// indexed under onto the target starting at `q_start`.
+struct ChainBlock {
+    q_chr:   String,
+    q_start: u64,
+    q_size:  u64,
+    reverse: bool,
+}
+
+/// Reads a UCSC chain file, gzipped or not, into an index of its aligned
+/// blocks keyed by the source chromosome without its `chr` prefix.
+fn read_chain(path: &Path) -> Result<IntervalIndex<ChainBlock>, PipelineError> {
+    debug!(path = %path.display(), "Reading chain file");
+    let mut file = std::fs::File::open(path)?;
+    let mut magic = [0; 2];
+    let gz = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    file.seek(std::io::SeekFrom::Start(0))?;
+    let file: Box<dyn std::io::Read> = if gz {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let invalid = |line: usize| {
+        PipelineError::InvalidChain {
+            path: path.to_path_buf(),
+            line,
+        }
+    };
+    let strip = |chr: &str| chr.strip_prefix("chr").unwrap_or(chr).to_string();
+    let mut blocks = vec![];
+    // source chromosome and position, and the block template for the chain
+    // being read
+    let mut current: Option<(String, u64, ChainBlock)> = None;
+    for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let num = |x: &str| x.parse::<u64>().map_err(|_| invalid(i + 1));
+        match fields.as_slice() {
+            [] => {},
+            ["chain", _, t_chr, _, _, t_start, _, q_chr, q_size, q_strand, q_start, _, ..] => {
+                current = Some((strip(t_chr), num(t_start)?, ChainBlock {
+                    q_chr:   strip(q_chr),
+                    q_start: num(q_start)?,
+                    q_size:  num(q_size)?,
+                    reverse: *q_strand == "-",
+                }));
+            },
+            [size, gaps @ ..] if gaps.is_empty() || gaps.len() == 2 => {
+                let Some((t_chr, t_pos, block)) = current.as_mut() else {
+                    return Err(invalid(i + 1));
+                };
+                let size = num(size)?;
+                blocks.push((t_chr.clone(), *t_pos, *t_pos + size, ChainBlock {
+                    q_chr:   block.q_chr.clone(),
+                    q_start: block.q_start,
+                    q_size:  block.q_size,
+                    reverse: block.reverse,
+                }));
+                if let [dt, dq] = gaps {
+                    *t_pos += size + num(dt)?;
+                    block.q_start += size + num(dq)?;
+                } else {
+                    // the last block of a chain has no gaps after it
+                    current = None;
+                }
+            },
+            _ => return Err(invalid(i + 1)),
+        }
+    }
+    debug!(blocks = blocks.len(), "Read chain file");
+    Ok(IntervalIndex::new(blocks))
+}
+
+/// Why a position could not be lifted, following liftOver's naming
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unlifted {
+    /// No aligned block covers the position
+    Deleted,
+    /// More than one aligned block covers the position
+    Duplicated,
+}
+
+/// Maps a 1-based position through a chain.
+fn lift(chain: &IntervalIndex<ChainBlock>, chr: &str, pos: u64) -> Result<(String, u64), Unlifted> {
+    let Some(p) = pos.checked_sub(1) else {
+        return Err(Unlifted::Deleted);
+    };
+    let mut hits = chain.find(chr, p);
+    let Some((t_start, _, block)) = hits.next() else {
+        return Err(Unlifted::Deleted);
+    };
+    if hits.next().is_some() {
+        return Err(Unlifted::Duplicated);
+    }
+    let q = block.q_start + (p - t_start);
+    let q = if block.reverse {
+        block.q_size - 1 - q
+    } else {
+        q
+    };
+    Ok((block.q_chr.clone(), q + 1))
+}
+
+/// Lifted coordinates per raw data row, None where the raw data already has
+/// the build
+pub struct Lifted {
+    hg19:     Option<Vec<Option<(String, u64)>>>,
+    hg38:     Option<Vec<Option<(String, u64)>>>,
+    /// Lifted from hg38 when --lift-to chm13 is set
+    chm13:    Option<Vec<Option<(String, u64)>>>,
+    /// Rows that failed to lift, with the reason
+    unlifted: Vec<(usize, Unlifted)>,
+}
+
+impl Lifted {
+    /// The lifted coordinates as a table, for --stop-after liftover
+    fn to_data(&self) -> Data {
+        let builds = [
+            ("hg19", &self.hg19),
+            ("hg38", &self.hg38),
+            ("chm13", &self.chm13),
+        ]
+        .into_iter()
+        .filter_map(|(build, x)| Some((build, x.as_ref()?)))
+        .collect::<Vec<_>>();
+        let mut header = vec!["row".to_string()];
+        for (build, _) in &builds {
+            header.push(format!("chr_{}", build));
+            header.push(format!("pos_{}", build));
+        }
+        let rows = builds.first().map_or(0, |(_, x)| x.len());
+        let data = (0..rows)
+            .map(|i| {
+                let mut r = vec![i.to_string()];
+                for (_, lifted) in &builds {
+                    match &lifted[i] {
+                        Some((chr, pos)) => r.extend([chr.clone(), pos.to_string()]),
+                        None => r.extend(["NA".to_string(), "NA".to_string()]),
+                    }
+                }
+                r
+            })
+            .collect();
+        Data { header, data }
+    }
+}
+
+/// Lifts every position through the chain at `path`, recording the rows that
+/// fail. Rows without an input position stay None.
+fn lift_all(
+    path: &Path,
+    input: &[Option<(String, u64)>],
+    unlifted: &mut Vec<(usize, Unlifted)>,
+) -> Result<Vec<Option<(String, u64)>>, PipelineError> {
+    let chain = read_chain(path)?;
+    let lifted = input
+        .par_iter()
+        .map(|x| x.as_ref().map(|(chr, pos)| lift(&chain, chr, *pos)))
+        .collect::<Vec<_>>();
+    Ok(lifted
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| {
+            match x? {
+                Ok(x) => Some(x),
+                Err(reason) => {
+                    unlifted.push((i, reason));
+                    None
+                },
+            }
+        })
+        .collect())
+}
+
+fn unlifted_output(ctx: &Ctx) -> Option<String> {
+    let path = ctx.args.unlifted_output.as_ref()?;
+    Some(path.replace("{trait_name}", &ctx.trait_name))
+}
+
+/// Writes the raw data rows that failed liftover with an `unlifted_reason`
+/// column, for --unlifted-output
+fn write_unlifted(ctx: &Ctx, raw_data: &Data, unlifted: &[(usize, Unlifted)], path: &str) {
+    let mut header = raw_data
+        .header
+        .iter()
+        .map(|x| x.as_str())
+        .collect::<Vec<_>>();
+    header.push("unlifted_reason");
+    info!(path, count = unlifted.len(), "Writing unlifted variants");
+    Data::write_rows(
+        path,
+        ctx.compress_threads(),
+        &header,
+        &[],
+        unlifted.iter().map(|(i, reason)| {
+            let mut r = raw_data.data[*i].clone();
+            r.push(format!("{:?}", reason).to_lowercase());
+            r
+        }),
+    );
+}
+
+/// Lifts the hg38 coordinates, lifted or from the raw data, to CHM13 for
+/// --lift-to chm13. Failures are only counted since the row keeps its hg19 and
+/// hg38 positions.
+fn lift_chm13(
+    ctx: &Ctx,
+    raw_data: &Data,
+    lifted: &mut Lifted,
+    chain: &Path,
+) -> Result<(), PipelineError> {
+    let input = match &lifted.hg38 {
+        Some(hg38) => hg38.clone(),
+        None => {
+            let chr = raw_data.idx("chr_hg38");
+            let pos = raw_data.idx("pos_hg38");
+            raw_data
+                .data
+                .par_iter()
+                .map(|r| Some((r[chr].clone(), r[pos].parse::<u64>().ok()?)))
+                .collect()
+        },
+    };
+    let mut unlifted = vec![];
+    lifted.chm13 = Some(lift_all(chain, &input, &mut unlifted)?);
+    info!(
+        unlifted = unlifted.len(),
+        "{} variants could not be lifted to CHM13",
+        unlifted.len()
+    );
+    ctx.report.set("unlifted_chm13", unlifted.len());
+    Ok(())
+}
+
+#[tracing::instrument(skip(ctx, raw_data))]
+fn liftover(ctx: &Ctx, raw_data: &Data) -> Result<Lifted, PipelineError> {
+    let chm13_chain = (ctx.args.lift_to == Some(LiftTarget::Chm13))
+        .then(|| ctx.chain_path("hg38", "hs1"))
+        .transpose()?;
+    if ["pos_hg19", "pos_hg38"]
+        .iter()
+        .all(|x| raw_data.idx_opt(x).is_some())
+    {
+        info!("Raw data already has hg19 and hg38 positions, skipping liftover");
+        ctx.report.set("liftover_skipped", true);
+        let mut lifted = Lifted {
+            hg19:     None,
+            hg38:     None,
+            chm13:    None,
+            unlifted: vec![],
+        };
+        if let Some(chain) = &chm13_chain {
+            lift_chm13(ctx, raw_data, &mut lifted, chain)?;
+        }
+        return Ok(lifted);
+    }
+    let Some(build) = ["hg16", "hg17", "hg18", "hg19", "hg38"]
+        .into_iter()
+        .find(|x| raw_data.header.contains(&format!("pos_{}", x)))
+    else {
+        return Err(PipelineError::NoPositionColumns);
+    };
+    debug!(build, "Checking position columns");
+    // resolve the chains up front so a missing one fails before any work
+    let to_hg19_chain = matches!(build, "hg16" | "hg17" | "hg18")
+        .then(|| ctx.chain_path(build, "hg19"))
+        .transpose()?;
+    let chain = if build == "hg38" {
+        ctx.chain_path("hg38", "hg19")?
+    } else {
+        ctx.chain_path("hg19", "hg38")?
+    };
+    let chr = raw_data.idx(&format!("chr_{}", build));
+    let pos = raw_data.idx(&format!("pos_{}", build));
+    let input = raw_data
+        .data
+        .par_iter()
+        .map(|r| Some((r[chr].clone(), r[pos].parse::<u64>().unwrap())))
+        .collect::<Vec<_>>();
+    let mut unlifted = vec![];
+    let mut lifted = match to_hg19_chain {
+        Some(to_hg19_chain) => {
+            let hg19 = lift_all(&to_hg19_chain, &input, &mut unlifted)?;
+            let hg38 = lift_all(&chain, &hg19, &mut unlifted)?;
+            Lifted {
+                hg19: Some(hg19),
+                hg38: Some(hg38),
+                chm13: None,
+                unlifted,
+            }
+        },
+        None if build == "hg38" => {
+            Lifted {
+                hg19: Some(lift_all(&chain, &input, &mut unlifted)?),
+                hg38: None,
+                chm13: None,
+                unlifted,
+            }
+        },
+        None => {
+            Lifted {
+                hg19: None,
+                hg38: Some(lift_all(&chain, &input, &mut unlifted)?),
+                chm13: None,
+                unlifted,
+            }
+        },
+    };
+    // the two hops of an hg16/hg17/hg18 lift record their failures separately
+    lifted.unlifted.sort_unstable_by_key(|x| x.0);
+    let (unlifted, total) = (lifted.unlifted.len(), raw_data.data.len());
+    info!(
+        unlifted,
+        total, "{} of {} variants unlifted", unlifted, total
+    );
+    if let Some(path) = unlifted_output(ctx) {
+        write_unlifted(ctx, raw_data, &lifted.unlifted, &path);
+    }
+    for reason in [Unlifted::Deleted, Unlifted::Duplicated] {
+        let count = lifted.unlifted.iter().filter(|x| x.1 == reason).count();
+        ctx.report.set_in(
+            "liftover_unlifted",
+            &format!("{:?}", reason).to_lowercase(),
+            count,
+        );
+    }
+    if let Some(chain) = &chm13_chain {
+        lift_chm13(ctx, raw_data, &mut lifted, chain)?;
+    }
+    Ok(lifted)
+}
+
+fn check_build(ctx: &Ctx, raw_data: &Data, dbsnp: &Dbsnp, stated: Option<&str>) {
+    let (stated, alternative) = match stated {
+        Some("hg19") => ("hg19", "hg38"),
+        Some("hg38") => ("hg38", "hg19"),
+        _ => {
+            warn!("Build check is only supported for studies on hg19 or hg38, skipping");
+            return;
+        },
+    };
+    let match_rate = |build: &str| {
+        let pos = if build == "hg19" { 1 } else { 4 };
+        let keys: HashSet<(&str, &str, &str, &str)> =
+            HashSet::from_par_iter(dbsnp.map.par_iter().map(|(key, _)| {
+                let x = dbsnp_key_parts(key);
+                (x[0], x[pos], x[2], x[3])
+            }));
+        let raw_idxs = [
+            raw_data.idx(&format!("chr_{}", stated)),
+            raw_data.idx(&format!("pos_{}", stated)),
+            raw_data.idx("ref"),
+            raw_data.idx("alt"),
+        ];
+        let matched = raw_data
+            .data
+            .par_iter()
+            .filter(|r| {
+                let (c, p, a, b) = (
+                    r[raw_idxs[0]].as_str(),
+                    r[raw_idxs[1]].as_str(),
+                    r[raw_idxs[2]].as_str(),
+                    r[raw_idxs[3]].as_str(),
+                );
+                keys.contains(&(c, p, a, b)) || keys.contains(&(c, p, b, a))
+            })
+            .count();
+        matched as f64 / raw_data.data.len().max(1) as f64
+    };
+    let stated_rate = match_rate(stated);
+    let alternative_rate = match_rate(alternative);
+    info!(
+        stated,
+        stated_rate, alternative, alternative_rate, "Checked build consistency"
+    );
+    if stated_rate < alternative_rate * 0.5 {
+        warn!(
+            "Only {:.1}% of variants match dbSNP assuming the legend's hg_version={}, but {:.1}% \
+             match assuming {}. Please double check the hg_version in the GWAS formatting legend",
+            stated_rate * 100.0,
+            stated,
+            alternative_rate * 100.0,
+            alternative
+        );
+    }
+    ctx.report.set(
+        "build_check",
+        serde_json::json!({
+            "stated_build": stated,
+            "stated_match_rate": stated_rate,
+            "alternative_build": alternative,
+            "alternative_match_rate": alternative_rate,
+        }),
+    );
+}
+
+fn dbsnp_shards(path: &str) -> Result<Vec<PathBuf>, PipelineError> {
+    let path_ref = Path::new(path);
+    let mut shards = if path_ref.is_dir() {
+        std::fs::read_dir(path_ref)?
+            .map(|x| x.map(|x| x.path()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|x| x.is_file())
+            .collect::<Vec<_>>()
+    } else if path.contains(['*', '?', '[']) {
+        glob::glob(path)
+            .map_err(|_| PipelineError::InvalidDbsnpGlob(path.to_string()))?
+            .map(|x| x.map_err(std::io::Error::from))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![path_ref.to_path_buf()]
+    };
+    shards.sort();
+    if shards.is_empty() {
+        return Err(PipelineError::NoDbsnpFiles(path.to_string()));
+    }
+    Ok(shards)
+}
+
+/// Counts the bytes read through it, to measure how far into a compressed file
+/// a decoder has got.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Estimates how many bytes dbSNP takes once loaded and indexed, by
+/// decompressing the start of the first shard and scaling the in-memory size of
+/// its rows by the compressed size of all the shards.
+fn estimate_dbsnp_bytes(shards: &[PathBuf]) -> Result<u64, PipelineError> {
+    let compressed = shards
+        .iter()
+        .map(|x| Ok(std::fs::metadata(x)?.len()))
+        .sum::<Result<u64, std::io::Error>>()?;
+    let file = std::fs::File::open(&shards[0])?;
+    let mut counter = CountingReader {
+        inner: file,
+        count: 0,
+    };
+    let mut sample_bytes = 0;
+    for line in std::io::BufReader::new(flate2::read::GzDecoder::new(&mut counter))
+        .lines()
+        .skip(1)
+        .map_while(Result::ok)
+    {
+        let fields = line.split('\t').count() as u64;
+        // the row's Vec, a String per field, and its map entry
+        sample_bytes += 24 + 24 * fields + line.len() as u64 + 88;
+        if sample_bytes > 1 << 20 {
+            break;
+        }
+    }
+    if counter.count == 0 {
+        return Ok(0);
+    }
+    Ok((compressed as f64 * sample_bytes as f64 / counter.count as f64) as u64)
+}
+
+/// Chromosomes, chromosome and position pairs (on either build), and rsids of
+/// the study, used to load only the relevant dbSNP records. Without positions
+/// records are kept by chromosome alone.
+struct DbsnpFilter<'a> {
+    chrs:      HashSet<&'a str>,
+    positions: Option<HashSet<(&'a str, &'a str)>>,
+    rsids:     HashSet<&'a str>,
+}
+
+impl DbsnpFilter<'_> {
+    fn new<'a>(raw_data: &'a Data, rsid_col: Option<&str>, by_position: bool) -> DbsnpFilter<'a> {
+        let mut chrs = HashSet::new();
+        let mut positions = HashSet::new();
+        for build in ["hg19", "hg38"] {
+            let chr = raw_data.idx(&format!("chr_{}", build));
+            let pos = raw_data.idx(&format!("pos_{}", build));
+            for r in &raw_data.data {
+                chrs.insert(r[chr].as_str());
+                if by_position {
+                    positions.insert((r[chr].as_str(), r[pos].as_str()));
+                }
+            }
+        }
+        let rsids = match rsid_col {
+            Some(col) => raw_data.col(col).collect(),
+            None => HashSet::new(),
+        };
+        DbsnpFilter {
+            chrs,
+            positions: by_position.then_some(positions),
+            rsids,
+        }
+    }
+
+    /// Whether to keep a dbSNP record, given its normalized chromosome
+    fn keeps(&self, chr: &str, pos_hg19: &str, pos_hg38: &str, rsid: &str) -> bool {
+        if self.rsids.contains(rsid) {
+            return true;
+        }
+        match &self.positions {
+            Some(positions) => {
+                positions.contains(&(chr, pos_hg19)) || positions.contains(&(chr, pos_hg38))
+            },
+            None => self.chrs.contains(chr),
+        }
+    }
+}
+
+/// Positions of chr, pos_hg19, ref, alt, pos_hg38, and rsid in a dbSNP header
+fn dbsnp_required_idxs(header: &[&str]) -> Result<[usize; 6], PipelineError> {
+    let required = ["chr", "pos_hg19", "ref", "alt", "pos_hg38", "rsid"];
+    let missing = required
+        .iter()
+        .filter(|x| !header.contains(x))
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(PipelineError::DbsnpColumnsMissing(missing));
+    }
+    Ok(required.map(|col| header.iter().position(|x| *x == col).unwrap()))
+}
+
+/// Key of a dbSNP record in a `DbsnpMap`: chr, pos_hg19, ref, alt, and
+/// pos_hg38 joined by tabs, so a study row is looked up with one allocation
+fn dbsnp_key(parts: [&str; 5]) -> String {
+    parts.join("\t")
+}
+
+/// Splits a `dbsnp_key` back into chr, pos_hg19, ref, alt, and pos_hg38
+fn dbsnp_key_parts(key: &str) -> [&str; 5] {
+    let mut parts = key.split('\t');
+    [(); 5].map(|_| parts.next().unwrap_or_default())
+}
+
+/// dbSNP records by `dbsnp_key`, holding the record's row in the files and the
+/// columns appended to matched rows
+type DbsnpMap = HashMap<String, (usize, Vec<String>)>;
+
+/// dbSNP as loaded for matching
+struct Dbsnp {
+    /// Names of the columns held in the map's values
+    columns:    Vec<String>,
+    map:        DbsnpMap,
+    /// Records that shared a key with an earlier one
+    duplicates: usize,
+}
+
+impl Dbsnp {
+    fn lookup(&self, key: [&str; 5]) -> Option<&[String]> {
+        self.map.get(&dbsnp_key(key)).map(|(_, x)| x.as_slice())
+    }
+
+    /// The key and columns of the first record in file order with each rsid.
+    /// Merged duplicates carry several rsids and are found by each of them.
+    fn by_rsid(&self) -> HashMap<&str, (&str, &[String])> {
+        let rsid = self.columns.iter().position(|x| x == "rsid").unwrap();
+        let mut by_rsid = HashMap::new();
+        for (key, (row, x)) in &self.map {
+            for id in x[rsid].split(';').filter(|x| *x != "NA") {
+                let e = by_rsid.entry(id).or_insert((key.as_str(), *row, x));
+                if *row < e.1 {
+                    *e = (key.as_str(), *row, x);
+                }
+            }
+        }
+        by_rsid
+            .into_iter()
+            .map(|(id, (key, _, x))| (id, (key, x.as_slice())))
+            .collect()
+    }
+}
+
+/// Streams the dbSNP shards in file order straight into a `DbsnpMap`, keeping
+/// the records `filter` keeps and resolving records with the same key by
+/// --dbsnp-dup. Lines are dropped or moved into the map as they are read, so
+/// no more than the kept records are ever held.
+fn read_dbsnp(ctx: &Ctx, filter: &DbsnpFilter) -> Result<Dbsnp, PipelineError> {
+    let shards = dbsnp_shards(&ctx.args.dbsnp_file)?;
+    debug!(shards = shards.len(), "Reading dbSNP shards");
+    let normalize = ctx.args.rsid_normalization != RsidNormalization::None;
+    let keep_other = ctx.args.rsid_normalization == RsidNormalization::KeepOther;
+    let mut columns: Option<Vec<String>> = None;
+    let mut map = DbsnpMap::new();
+    let mut records = 0;
+    let mut duplicates = 0;
+    let (mut changed, mut other) = (0, 0);
+    for shard in &shards {
+        let file = flate2::read::GzDecoder::new(std::fs::File::open(shard)?);
+        let mut lines = std::io::BufReader::new(file).lines();
+        let header = loop {
+            match lines.next().transpose()? {
+                Some(l) if l.starts_with("##") => {},
+                l => break l.unwrap_or_default(),
+            }
+        };
+        let header = header.split('\t').collect::<Vec<_>>();
+        let [chr, pos_hg19, ref_, alt, pos_hg38, rsid] = dbsnp_required_idxs(&header)?;
+        let keys = [chr, pos_hg19, ref_, alt, pos_hg38];
+        let value_idxs = (0..header.len())
+            .filter(|i| !keys.contains(i))
+            .filter(|i| {
+                !ctx.args.dbsnp_chunk_columns
+                    || *i == rsid
+                    || ctx.args.af_columns.iter().any(|x| x == header[*i])
+            })
+            .collect::<Vec<_>>();
+        let shard_columns = value_idxs
+            .iter()
+            .map(|i| header[*i].to_string())
+            .collect::<Vec<_>>();
+        match &columns {
+            Some(expected) if *expected != shard_columns => {
+                return Err(PipelineError::DbsnpShardHeader {
+                    shard:    shard.clone(),
+                    expected: expected.clone(),
+                    found:    shard_columns,
+                });
+            },
+            Some(_) => {},
+            None => columns = Some(shard_columns),
+        }
+        let rsid_value = value_idxs.iter().position(|i| *i == rsid).unwrap();
+        for line in lines {
+            let line = line?;
+            let r = line.split('\t').collect::<Vec<_>>();
+            let get = |i: usize| r.get(i).copied().unwrap_or("NA");
+            let c = normalize_chr(get(chr)).unwrap_or(get(chr));
+            if !filter.keeps(c, get(pos_hg19), get(pos_hg38), get(rsid)) {
+                continue;
+            }
+            let mut value = value_idxs
+                .iter()
+                .map(|i| get(*i).to_string())
+                .collect::<Vec<_>>();
+            if normalize {
+                let (c, o) = normalize_rsid_field(&mut value[rsid_value], keep_other);
+                changed += c;
+                other += o;
+            }
+            let key = dbsnp_key([c, get(pos_hg19), get(ref_), get(alt), get(pos_hg38)]);
+            match map.entry(key) {
+                std::collections::hash_map::Entry::Occupied(mut e) => {
+                    duplicates += 1;
+                    match ctx.args.dbsnp_dup {
+                        DbsnpDup::Last => {
+                            e.insert((records, value));
+                        },
+                        DbsnpDup::Merge => {
+                            let from = &value[rsid_value];
+                            let into = &mut e.get_mut().1[rsid_value];
+                            if !into.split(';').any(|x| x == from) {
+                                into.push(';');
+                                into.push_str(from);
+                            }
+                        },
+                        DbsnpDup::First | DbsnpDup::Error => {},
+                    }
+                },
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    e.insert((records, value));
+                },
+            }
+            records += 1;
+        }
+    }
+    if normalize {
+        report_rsid_normalization(ctx, "dbsnp", changed, other);
+    }
+    Ok(Dbsnp {
+        columns: columns.unwrap_or_default(),
+        map,
+        duplicates,
+    })
+}
+
+/// chr, pos, and both allele orders of every matched row, for the passes that
+/// only consider rows no earlier pass matched
+fn matched_keys<'a>(
+    data: &'a Data,
+    idxs: &[usize; 5],
+) -> HashSet<(&'a str, &'a str, &'a str, &'a str)> {
+    HashSet::from_iter(data.data.iter().flat_map(|r| {
+        [
+            (
+                r[idxs[0]].as_str(),
+                r[idxs[1]].as_str(),
+                r[idxs[2]].as_str(),
+                r[idxs[3]].as_str(),
+            ),
+            (
+                r[idxs[0]].as_str(),
+                r[idxs[1]].as_str(),
+                r[idxs[3]].as_str(),
+                r[idxs[2]].as_str(),
+            ),
+        ]
+    }))
+}
+
+fn check_liftover_shift(ctx: &Ctx, raw_data: &mut Data, stated_build: &str) {
+    let lifted_build = if stated_build == "hg19" {
+        "hg38"
+    } else {
+        "hg19"
+    };
+    let chr_from = raw_data.idx(&format!("chr_{}", stated_build));
+    let pos_from = raw_data.idx(&format!("pos_{}", stated_build));
+    let chr_to = raw_data.idx(&format!("chr_{}", lifted_build));
+    let pos_to = raw_data.idx(&format!("pos_{}", lifted_build));
+    let is_cross_chr = |r: &Vec<String>| {
+        r[pos_to] != "NA" && normalize_chr(&r[chr_from]) != normalize_chr(&r[chr_to])
+    };
+    let is_too_far = |r: &Vec<String>| {
+        let Some(max) = ctx.args.max_liftover_shift else {
+            return false;
+        };
+        match (r[pos_from].parse::<u64>(), r[pos_to].parse::<u64>()) {
+            (Ok(from), Ok(to)) => from.abs_diff(to) > max,
+            _ => false,
+        }
+    };
+    let cross_chr = raw_data.data.iter().filter(|r| is_cross_chr(r)).count();
+    ctx.report.set("liftover_cross_chromosome", cross_chr);
+    if cross_chr > 0 {
+        warn!(
+            count = cross_chr,
+            "Liftover moved variants to a different chromosome"
+        );
+    }
+    if ctx.args.max_liftover_shift.is_none() {
+        return;
+    }
+    let before = raw_data.data.len();
+    raw_data.data.retain(|r| !is_cross_chr(r) && !is_too_far(r));
+    let dropped = before - raw_data.data.len();
+    ctx.report.set("liftover_shift_dropped", dropped);
+    if dropped > 0 {
+        warn!(
+            count = dropped,
+            max = ctx.args.max_liftover_shift,
+            "Removed variants exceeding the maximum liftover shift"
+        );
+    }
+}
+
+#[tracing::instrument(skip(ctx, raw_data, lifted))]
+fn dbsnp_matching(
+    ctx: &Ctx,
+    mut raw_data: Data,
+    lifted: Lifted,
+) -> Result<(Data, Data), PipelineError> {
+    debug!("Adding lifted coordinates");
+    let stated_build = ["hg19", "hg38"]
+        .into_iter()
+        .find(|x| raw_data.header.contains(&format!("pos_{}", x)));
+    let lifted = [
+        ("hg19", lifted.hg19),
+        ("hg38", lifted.hg38),
+        ("chm13", lifted.chm13),
+    ]
+    .into_iter()
+    .filter_map(|(build, x)| Some((build, x?)))
+    .collect::<Vec<_>>();
+    for (build, coords) in &lifted {
+        raw_data.header.push(format!("chr_{}", build));
+        raw_data.header.push(format!("pos_{}", build));
+        ctx.report.set(
+            &format!("lifted_{}", build),
+            coords.iter().flatten().count(),
+        );
+    }
+    let header_len = raw_data.header.len();
+    raw_data.data.par_iter_mut().enumerate().for_each(|(i, r)| {
+        reserve_to(r, header_len);
+        for (_, coords) in &lifted {
+            match &coords[i] {
+                Some((chr, pos)) => {
+                    r.push(chr.clone());
+                    r.push(pos.to_string());
+                },
+                None => {
+                    r.push("NA".to_string());
+                    r.push("NA".to_string());
+                },
+            }
+        }
+    });
+    drop(lifted);
+    // raw data that already carries both builds may only name the chromosome
+    // once
+    for (build, other) in [("hg19", "hg38"), ("hg38", "hg19")] {
+        let chr = format!("chr_{}", build);
+        if raw_data.idx_opt(&chr).is_some() {
+            continue;
+        }
+        if let Some(other) = raw_data.idx_opt(&format!("chr_{}", other)) {
+            raw_data.header.push(chr);
+            raw_data.data.par_iter_mut().for_each(|r| {
+                let chr = r[other].clone();
+                r.push(chr);
+            });
+        }
+    }
+
+    if let Some(stated_build) = stated_build {
+        check_liftover_shift(ctx, &mut raw_data, stated_build);
+    }
+
+    // dbSNP's rsid column is appended when matching, so the input rsid is kept
+    // under its own name for the rsid fallback
+    let match_by_rsid = ctx.args.match_by_rsid && raw_data.idx_opt("rsid").is_some();
+    if match_by_rsid {
+        let rsid = raw_data.idx("rsid");
+        raw_data.header.push("input_rsid".to_string());
+        raw_data.data.par_iter_mut().for_each(|r| {
+            let x = r[rsid].clone();
+            r.push(x);
+        });
+    } else if ctx.args.match_by_rsid {
+        warn!("The raw data has no rsid column, skipping rsid matching");
+    }
+    // keep the input rsid around until dbSNP is loaded so it can be checked
+    let coordinate_validation =
+        ctx.args.coordinate_validation && raw_data.idx_opt("rsid").is_some();
+    if coordinate_validation {
+        raw_data.rename("rsid", "pos_discrepant");
+    } else if ctx.args.coordinate_validation {
+        warn!("The raw data has no rsid column, skipping coordinate validation");
+    }
+    debug!("Reordering columns");
+    let mut order = vec![
+        "chr_hg19",
+        "pos_hg19",
+        "ref",
+        "alt",
+        "effect_size",
+        "standard_error",
+        "EAF",
+        "pvalue",
+        "pvalue_het",
+        "N_total",
+        "N_case",
+        "N_ctrl",
+        "chr_hg38",
+        "pos_hg38",
+    ];
+    if ctx.args.lift_to == Some(LiftTarget::Chm13) {
+        order.extend(["chr_chm13", "pos_chm13"]);
+    }
+    if coordinate_validation {
+        order.push("pos_discrepant");
+    }
+    if match_by_rsid {
+        order.push("input_rsid");
+    }
+    push_keep_columns(ctx, &mut order);
+    raw_data.reorder(&order);
+    if ctx.args.drop_palindromic {
+        let (ref_, alt) = (raw_data.idx("ref"), raw_data.idx("alt"));
+        let before = raw_data.data.len();
+        raw_data.data.retain(|r| !is_palindromic(&r[ref_], &r[alt]));
+        let dropped = before - raw_data.data.len();
+        info!(count = dropped, "Dropped palindromic variants");
+        ctx.report.set("palindromic_dropped", dropped);
+    }
+    // raw_data.write("dbsnp.e.txt.gz");
+    debug!(len = raw_data.data.len(), "Raw data after bed matching");
+
+    debug!("Reading dbSNP file");
+    let filtered = match ctx.args.dbsnp_memory_limit {
+        Some(limit) => {
+            let estimate = estimate_dbsnp_bytes(&dbsnp_shards(&ctx.args.dbsnp_file)?)?;
+            let estimate_gb = estimate as f64 / 1e9;
+            ctx.report.set("dbsnp_estimated_gb", estimate_gb);
+            estimate_gb > limit
+        },
+        None => false,
+    };
+    if filtered {
+        info!("dbSNP would exceed --dbsnp-memory-limit, loading only the study's positions");
+        ctx.report.set("dbsnp_strategy", "position_filtered");
+    } else {
+        ctx.report.set("dbsnp_strategy", "in_memory");
+    }
+    let rsid_col = if match_by_rsid {
+        Some("input_rsid")
+    } else {
+        coordinate_validation.then_some("pos_discrepant")
+    };
+    let dbsnp = read_dbsnp(ctx, &DbsnpFilter::new(&raw_data, rsid_col, filtered))?;
+    debug!(records = dbsnp.map.len(), "Read dbSNP records");
+    let (af_columns, absent_af_columns): (Vec<_>, Vec<_>) = ctx
+        .args
+        .af_columns
+        .iter()
+        .map(|x| x.as_str())
+        .partition(|x| dbsnp.columns.iter().any(|c| c == x));
+    if !absent_af_columns.is_empty() {
+        warn!(
+            absent = ?absent_af_columns,
+            "Allele frequency columns are missing from the dbSNP file and will not be output"
+        );
+    }
+    ctx.report.set("absent_af_columns", absent_af_columns);
+    let duplicates = dbsnp.duplicates;
+    ctx.report.set("dbsnp_duplicates", duplicates);
+    if duplicates > 0 {
+        warn!(
+            duplicates,
+            policy = ?ctx.args.dbsnp_dup,
+            "dbSNP contains records with the same chr/pos/ref/alt"
+        );
+        if ctx.args.dbsnp_dup == DbsnpDup::Error {
+            return Err(PipelineError::DbsnpDuplicates(duplicates));
+        }
+    }
+    if ctx.args.check_build {
+        check_build(ctx, &raw_data, &dbsnp, stated_build);
+    }
+    if coordinate_validation {
+        validate_coordinates(ctx, &mut raw_data, &dbsnp);
+    }
+    debug!("Getting raw data indexes");
+    let raw_data_idxs = [
+        raw_data.idx("chr_hg19"),
+        raw_data.idx("pos_hg19"),
+        raw_data.idx("ref"),
+        raw_data.idx("alt"),
+        raw_data.idx("pos_hg38"),
+    ];
+    let raw_data_merged_flipped_idxs = [
+        raw_data.idx("chr_hg19"),
+        raw_data.idx("pos_hg19"),
+        raw_data.idx("alt"),
+        raw_data.idx("ref"),
+        raw_data.idx("pos_hg38"),
+    ];
+    let mut raw_data_merged = raw_data.clone();
+    let raw_data_merged_data = std::mem::take(&mut raw_data_merged.data);
+    debug!(columns = ?dbsnp.columns, "Adding dbSNP columns");
+    raw_data_merged.header.extend(dbsnp.columns.iter().cloned());
+    raw_data_merged.header.push("unique_id".to_string());
+    raw_data_merged.header.push("harmonization".to_string());
+    let unique_id_idx = raw_data_merged.idx("unique_id");
+    let mut raw_data_flipped = raw_data_merged.clone();
+    raw_data_flipped.data = raw_data.data.clone();
+    debug!(header = ?raw_data_merged.header, "Header");
+    debug!(idxs = ?raw_data_idxs, "Raw data indexes");
+    let header_len = raw_data_merged.header.len();
+    raw_data_merged.data = raw_data_merged_data
+        .into_par_iter()
+        .filter_map(|mut r| {
+            reserve_to(&mut r, header_len);
+            let dbsnp_data = dbsnp.lookup(raw_data_idxs.map(|i| r[i].as_str()))?;
+            r.extend(dbsnp_data.iter().cloned());
+            r.push(format!(
+                "{}_{}_{}_{}",
+                r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]],
+            ));
+            r.push("direct".to_string());
+            Some(r)
+        })
+        .collect::<Vec<_>>();
+    debug!("Flipping alleles");
+    let mut raw_data_flipped_data = std::mem::take(&mut raw_data_flipped.data);
+    let header_len = raw_data_flipped.header.len();
+    raw_data_flipped_data = raw_data_flipped_data
+        .into_par_iter()
+        .filter_map(|mut r| {
+            reserve_to(&mut r, header_len);
+            let dbsnp_data = dbsnp.lookup(raw_data_merged_flipped_idxs.map(|i| r[i].as_str()))?;
+            r.extend(dbsnp_data.iter().cloned());
+            r.push(format!(
+                "{}_{}_{}_{}",
+                r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]],
+            ));
+            r.push("flipped".to_string());
+            Some(r)
+        })
+        .collect::<Vec<_>>();
+    debug!("Merging flipped alleles");
+    let unique_ids: HashSet<&str> = HashSet::from_iter(
+        raw_data_merged
+            .data
+            .iter()
+            .map(|x| x[unique_id_idx].as_str()),
+    );
+    raw_data_flipped.data = raw_data_flipped_data
+        .into_par_iter()
+        .filter(|x| !unique_ids.contains(x[unique_id_idx].as_str()))
+        .collect::<Vec<_>>();
+    let allele_cols = AlleleCols::new(&raw_data_flipped);
+    raw_data_flipped.data.par_iter_mut().for_each(|r| {
+        allele_cols.flip(ctx, r);
+        r[unique_id_idx] = format!(
+            "{}_{}_{}_{}",
+            r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]]
+        );
+    });
+    raw_data_merged.data.extend(raw_data_flipped.data);
+    // raw data rows matched by a pass that changes the alleles or coordinates,
+    // which can't be found in the merged data by their raw values
+    let mut recovered_rows = HashSet::new();
+    if ctx.args.strand_flip_with_complement {
+        debug!("Matching complemented alleles");
+        let matched = matched_keys(&raw_data_merged, &raw_data_idxs);
+        let header_len = raw_data_merged.header.len();
+        let strand_flipped = raw_data
+            .data
+            .par_iter()
+            .enumerate()
+            .filter_map(|(i, r)| {
+                if matched.contains(&(
+                    r[raw_data_idxs[0]].as_str(),
+                    r[raw_data_idxs[1]].as_str(),
+                    r[raw_data_idxs[2]].as_str(),
+                    r[raw_data_idxs[3]].as_str(),
+                )) {
+                    return None;
+                }
+                // palindromic SNPs look identical on both strands, so complementing
+                // them can't tell us anything
+                if is_palindromic(&r[raw_data_idxs[2]], &r[raw_data_idxs[3]]) {
+                    return None;
+                }
+                let ref_ = complement(&r[raw_data_idxs[2]])?;
+                let alt = complement(&r[raw_data_idxs[3]])?;
+                let dbsnp_data = dbsnp.lookup([
+                    r[raw_data_idxs[0]].as_str(),
+                    r[raw_data_idxs[1]].as_str(),
+                    ref_,
+                    alt,
+                    r[raw_data_idxs[4]].as_str(),
+                ])?;
+                let mut r = r.clone();
+                reserve_to(&mut r, header_len);
+                r[raw_data_idxs[2]] = ref_.to_string();
+                r[raw_data_idxs[3]] = alt.to_string();
+                r.extend(dbsnp_data.iter().cloned());
+                r.push(format!(
+                    "{}_{}_{}_{}",
+                    r[raw_data_idxs[0]],
+                    r[raw_data_idxs[1]],
+                    r[raw_data_idxs[2]],
+                    r[raw_data_idxs[3]],
+                ));
+                r.push("strand_flipped".to_string());
+                Some((i, r))
+            })
+            .collect::<Vec<_>>();
+        info!(
+            count = strand_flipped.len(),
+            "Recovered variants by complementing alleles"
+        );
+        ctx.report.set("strand_flipped", strand_flipped.len());
+        for (i, r) in strand_flipped {
+            recovered_rows.insert(i);
+            raw_data_merged.data.push(r);
+        }
+    }
+    if match_by_rsid {
+        debug!("Matching remaining variants by rsid");
+        let input_rsid = raw_data.idx("input_rsid");
+        let chr_hg38 = raw_data.idx("chr_hg38");
+        let by_rsid = dbsnp.by_rsid();
+        let matched = matched_keys(&raw_data_merged, &raw_data_idxs);
+        let header_len = raw_data_merged.header.len();
+        let rsid_matched = raw_data
+            .data
+            .par_iter()
+            .enumerate()
+            .filter_map(|(i, r)| {
+                if recovered_rows.contains(&i)
+                    || matched.contains(&(
+                        r[raw_data_idxs[0]].as_str(),
+                        r[raw_data_idxs[1]].as_str(),
+                        r[raw_data_idxs[2]].as_str(),
+                        r[raw_data_idxs[3]].as_str(),
+                    ))
+                {
+                    return None;
+                }
+                let (key, dbsnp_data) = by_rsid.get(r[input_rsid].as_str())?;
+                let key = dbsnp_key_parts(key);
+                let swapped = if (r[raw_data_idxs[2]].as_str(), r[raw_data_idxs[3]].as_str())
+                    == (key[2], key[3])
+                {
+                    false
+                } else if (r[raw_data_idxs[3]].as_str(), r[raw_data_idxs[2]].as_str())
+                    == (key[2], key[3])
+                {
+                    true
+                } else {
+                    return None;
+                };
+                let mut r = r.clone();
+                reserve_to(&mut r, header_len);
+                r[raw_data_idxs[0]] = key[0].to_string();
+                r[raw_data_idxs[1]] = key[1].to_string();
+                r[raw_data_idxs[4]] = key[4].to_string();
+                r[chr_hg38] = key[0].to_string();
+                r.extend(dbsnp_data.iter().cloned());
+                r.push(String::new());
+                r.push(if swapped { "rsid_flipped" } else { "rsid" }.to_string());
+                if swapped {
+                    allele_cols.flip(ctx, &mut r);
+                }
+                r[unique_id_idx] = format!(
+                    "{}_{}_{}_{}",
+                    r[raw_data_idxs[0]],
+                    r[raw_data_idxs[1]],
+                    r[raw_data_idxs[2]],
+                    r[raw_data_idxs[3]],
+                );
+                Some((i, r))
+            })
+            .collect::<Vec<_>>();
+        info!(
+            count = rsid_matched.len(),
+            "Matched variants to dbSNP by rsid"
+        );
+        ctx.report.set("rsid_matched", rsid_matched.len());
+        for (i, r) in rsid_matched {
+            recovered_rows.insert(i);
+            raw_data_merged.data.push(r);
+        }
+    }
+    if let Some(af) = &ctx.args.resolve_palindromic_by_freq {
+        if af_columns.contains(&af.as_str()) {
+            resolve_palindromic(ctx, &mut raw_data_merged, af);
+        } else {
+            warn!(
+                af,
+                "--resolve-palindromic-by-freq column is not one of the --af-columns in the dbSNP \
+                 file, skipping"
+            );
+        }
+    }
+    if ctx.args.deterministic {
+        raw_data_merged
+            .data
+            .sort_by(|a, b| a[unique_id_idx].cmp(&b[unique_id_idx]));
+    }
+    let before = raw_data_merged.data.len();
+    match ctx.args.duplicate_ids {
+        DuplicateIds::Drop => {
+            let mut seen = HashSet::new();
+            raw_data_merged
+                .data
+                .retain(|x| seen.insert(x[unique_id_idx].as_str().to_string()));
+            let dropped = before - raw_data_merged.data.len();
+            if dropped > 0 {
+                warn!(
+                    count = dropped,
+                    "Dropped variants with duplicate unique ids"
+                );
+            }
+            ctx.report.set("duplicate_ids_dropped", dropped);
+        },
+        DuplicateIds::Suffix => {
+            let mut seen = HashMap::new();
+            let mut suffixed = 0;
+            for r in &mut raw_data_merged.data {
+                let n = seen.entry(r[unique_id_idx].clone()).or_insert(0);
+                *n += 1;
+                if *n > 1 {
+                    r[unique_id_idx] = format!("{}_{}", r[unique_id_idx], n);
+                    suffixed += 1;
+                }
+            }
+            if suffixed > 0 {
+                info!(
+                    count = suffixed,
+                    "Suffixed variants with duplicate unique ids"
+                );
+            }
+            ctx.report.set("duplicate_ids_suffixed", suffixed);
+        },
+    }
+    debug!("Merging missing data");
+    let mut new_order = vec![
+        "rsid",
+        "unique_id",
+        "chr_hg19",
+        "pos_hg19",
+        "ref",
+        "alt",
+        "effect_size",
+        "standard_error",
+        "EAF",
+        "pvalue",
+        "pvalue_het",
+        "N_total",
+        "N_case",
+        "N_ctrl",
+        "chr_hg38",
+        "pos_hg38",
+    ];
+    if ctx.args.lift_to == Some(LiftTarget::Chm13) {
+        new_order.extend(["chr_chm13", "pos_chm13"]);
+    }
+    new_order.extend(af_columns);
+    new_order.push("harmonization");
+    if coordinate_validation {
+        new_order.push("pos_discrepant");
+    }
+    push_keep_columns(ctx, &mut new_order);
+    debug!("Constructing raw unique ids");
+    let raw_unique_ids: HashSet<(&str, &str, &str, &str)> = HashSet::from_par_iter(
+        raw_data_merged
+            .data
+            .par_iter()
+            .map(|r| {
+                (
+                    r[raw_data_idxs[0]].as_str(),
+                    r[raw_data_idxs[1]].as_str(),
+                    r[raw_data_idxs[2]].as_str(),
+                    r[raw_data_idxs[3]].as_str(),
+                )
+            })
+            .chain(raw_data_merged.data.par_iter().map(|r| {
+                (
+                    r[raw_data_idxs[0]].as_str(),
+                    r[raw_data_idxs[1]].as_str(),
+                    r[raw_data_idxs[3]].as_str(),
+                    r[raw_data_idxs[2]].as_str(),
+                )
+            })),
+    );
+    let mut variants_by_chr = BTreeMap::new();
+    for r in &raw_data.data {
+        *variants_by_chr
+            .entry(r[raw_data_idxs[0]].clone())
+            .or_insert(0usize) += 1;
+    }
+    let pos_hg19 = raw_data.idx("pos_hg19");
+    let pos_hg38 = raw_data.idx("pos_hg38");
+    debug!("Constructing missing data");
+    let header = raw_data.header.clone();
+    let raw_data_missing = raw_data
+        .data
+        .into_par_iter()
+        .enumerate()
+        .filter(|(i, r)| {
+            !recovered_rows.contains(i)
+                && !raw_unique_ids.contains(&(
+                    r[raw_data_idxs[0]].as_str(),
+                    r[raw_data_idxs[1]].as_str(),
+                    r[raw_data_idxs[2]].as_str(),
+                    r[raw_data_idxs[3]].as_str(),
+                ))
+                && r[pos_hg19] != "NA"
+                && r[pos_hg38] != "NA"
+                && r[pos_hg19] != "NaN"
+                && r[pos_hg38] != "NaN"
+        })
+        .map(|(_, r)| r)
+        .collect::<Vec<_>>();
+    let mut raw_data_missing = Data {
+        header,
+        data: raw_data_missing,
+    };
+    debug!(
+        header = ?raw_data.header,
+        len = raw_data.header.len(),
+        "Raw data header"
+    );
+    debug!(
+        header = ?raw_data_merged.header,
+        len = raw_data_merged.header.len(),
+        "Merged data header"
+    );
+    debug!(
+        header = ?raw_data_missing.header,
+        len = raw_data_missing.header.len(),
+        "Missing data header"
+    );
+    debug!("Reordering columns");
+    raw_data_merged.reorder(&new_order);
+    raw_data_missing
+        .header
+        .extend(dbsnp.columns.iter().cloned());
+    raw_data_missing.header.push("unique_id".to_string());
+    raw_data_missing.header.push("harmonization".to_string());
+    let header_len = raw_data_missing.header.len();
+    raw_data_missing.data.par_iter_mut().for_each(|r| {
+        reserve_to(r, header_len);
+        r.extend(dbsnp.columns.iter().map(|_| "NA".to_string()));
+        r.push(format!(
+            "{}_{}_{}_{}",
+            r[raw_data_idxs[0]], r[raw_data_idxs[1]], r[raw_data_idxs[2]], r[raw_data_idxs[3]]
+        ));
+        r.push("unmatched".to_string());
+    });
+    debug!(header = ?raw_data_missing.header);
+    if let Some(r) = raw_data_missing.data.first() {
+        assert_eq!(raw_data_missing.header.len(), r.len());
+    }
+    raw_data_missing.reorder(&new_order);
+    debug!(header = ?raw_data_merged.header);
+
+    if let Some(r) = raw_data_merged.data.first() {
+        assert_eq!(raw_data_merged.header.len(), r.len());
+    }
     debug!(header = ?raw_data_missing.header);
-    assert_eq!(
-        raw_data_missing.header.len(),
-        raw_data_missing.data[0].len()
+    if let Some(r) = raw_data_missing.data.first() {
+        assert_eq!(raw_data_missing.header.len(), r.len());
+    }
+    report_chr_match_rates(ctx, variants_by_chr, &raw_data_merged, &raw_data_missing);
+    Ok((raw_data_merged, raw_data_missing))
+}
+
+/// Puts matched palindromic SNPs on the strand whose EAF is closer to dbSNP's
+/// allele frequency in `af`. A variant that reads better on the other strand
+/// keeps dbSNP's alleles with its effect negated: a direct match becomes
+/// `palindromic_flipped`, and a flipped one ends up with its raw effect and
+/// complemented alleles, which is `strand_flipped`. Variants with EAF within
+/// 0.1 of 0.5 can't be told apart and are left as matched.
+fn resolve_palindromic(ctx: &Ctx, data: &mut Data, af: &str) {
+    let af = data.idx(af);
+    let eaf = data.idx("EAF");
+    let harmonization = data.idx("harmonization");
+    let allele_cols = AlleleCols::new(data);
+    let flipped = AtomicUsize::new(0);
+    let ambiguous = AtomicUsize::new(0);
+    data.data.par_iter_mut().for_each(|r| {
+        if !matches!(r[harmonization].as_str(), "direct" | "flipped")
+            || !is_palindromic(&r[allele_cols.ref_], &r[allele_cols.alt])
+        {
+            return;
+        }
+        let (Ok(f), Ok(p)) = (r[eaf].parse::<f64>(), r[af].parse::<f64>()) else {
+            return;
+        };
+        if (f - 0.5).abs() < 0.1 {
+            ambiguous.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if (f - p).abs() <= (1.0 - f - p).abs() {
+            return;
+        }
+        allele_cols.flip(ctx, r);
+        let ref_ = complement(&r[allele_cols.ref_]).unwrap();
+        let alt = complement(&r[allele_cols.alt]).unwrap();
+        r[allele_cols.ref_] = ref_.to_string();
+        r[allele_cols.alt] = alt.to_string();
+        r[harmonization] = if r[harmonization] == "direct" {
+            "palindromic_flipped"
+        } else {
+            "strand_flipped"
+        }
+        .to_string();
+        flipped.fetch_add(1, Ordering::Relaxed);
+    });
+    let flipped = flipped.into_inner();
+    let ambiguous = ambiguous.into_inner();
+    info!(
+        flipped,
+        ambiguous, "Resolved palindromic variants by allele frequency"
+    );
+    ctx.report
+        .set_in("palindromic_resolution", "flipped", flipped);
+    ctx.report
+        .set_in("palindromic_resolution", "ambiguous", ambiguous);
+}
+
+/// Replaces the input rsid in `pos_discrepant` with the distance between the
+/// variant's position and dbSNP's position for that rsid, on hg19 where both
+/// have one and otherwise hg38. Variants whose rsid is on another chromosome
+/// get `other_chr`, and those without a dbSNP rsid stay NA.
+fn validate_coordinates(ctx: &Ctx, raw_data: &mut Data, dbsnp: &Dbsnp) {
+    let by_rsid = dbsnp.by_rsid();
+    let col = raw_data.idx("pos_discrepant");
+    let chr = raw_data.idx("chr_hg19");
+    let pos_hg19 = raw_data.idx("pos_hg19");
+    let pos_hg38 = raw_data.idx("pos_hg38");
+    let checked = AtomicUsize::new(0);
+    let discrepant = AtomicUsize::new(0);
+    raw_data.data.par_iter_mut().for_each(|r| {
+        let Some((key, _)) = by_rsid.get(r[col].as_str()) else {
+            r[col] = "NA".to_string();
+            return;
+        };
+        let [d_chr, d_pos_hg19, _, _, d_pos_hg38] = dbsnp_key_parts(key);
+        let distance =
+            |a: &str, b: &str| Some(a.parse::<i64>().ok()?.abs_diff(b.parse::<i64>().ok()?));
+        let value = if r[chr] != "NA" && r[chr] != d_chr {
+            Some("other_chr".to_string())
+        } else {
+            distance(&r[pos_hg19], d_pos_hg19)
+                .or_else(|| distance(&r[pos_hg38], d_pos_hg38))
+                .map(|x| x.to_string())
+        };
+        let Some(value) = value else {
+            r[col] = "NA".to_string();
+            return;
+        };
+        checked.fetch_add(1, Ordering::Relaxed);
+        if value != "0" {
+            discrepant.fetch_add(1, Ordering::Relaxed);
+        }
+        r[col] = value;
+    });
+    let checked = checked.into_inner();
+    let discrepant = discrepant.into_inner();
+    if discrepant > 0 {
+        warn!(
+            checked,
+            discrepant, "Variant positions disagree with dbSNP's position for their rsid"
+        );
+    }
+    ctx.report
+        .set_in("coordinate_validation", "checked", checked);
+    ctx.report
+        .set_in("coordinate_validation", "discrepant", discrepant);
+}
+
+/// Reports, per chromosome (as chr_hg19), how many variants went in, how they
+/// matched dbSNP, and how many were missing, so a misnamed contig shows up as a
+/// chromosome with no matches
+fn report_chr_match_rates(
+    ctx: &Ctx,
+    variants_by_chr: BTreeMap<String, usize>,
+    merged: &Data,
+    missing: &Data,
+) {
+    let mut counts: BTreeMap<String, BTreeMap<&str, usize>> = variants_by_chr
+        .into_iter()
+        .map(|(chr, n)| (chr, BTreeMap::from([("in", n)])))
+        .collect();
+    let chr = merged.idx("chr_hg19");
+    let harmonization = merged.idx("harmonization");
+    for r in &merged.data {
+        *counts
+            .entry(r[chr].clone())
+            .or_default()
+            .entry(r[harmonization].as_str())
+            .or_insert(0) += 1;
+    }
+    let chr = missing.idx("chr_hg19");
+    for r in &missing.data {
+        *counts
+            .entry(r[chr].clone())
+            .or_default()
+            .entry("missing")
+            .or_insert(0) += 1;
+    }
+    let mut by_chr = serde_json::Map::new();
+    for (chr, c) in counts {
+        let get = |k| c.get(k).copied().unwrap_or(0);
+        let matched = get("direct")
+            + get("flipped")
+            + get("strand_flipped")
+            + get("rsid")
+            + get("rsid_flipped")
+            + get("palindromic_flipped");
+        if matched == 0 && chr != "NA" {
+            warn!(
+                chr,
+                variants = get("in"),
+                "No variants matched dbSNP on chromosome"
+            );
+        }
+        by_chr.insert(
+            chr,
+            serde_json::json!({
+                "in": get("in"),
+                "direct": get("direct"),
+                "flipped": get("flipped"),
+                "strand_flipped": get("strand_flipped"),
+                "palindromic_flipped": get("palindromic_flipped"),
+                "rsid": get("rsid") + get("rsid_flipped"),
+                "missing": get("missing"),
+            }),
+        );
+    }
+    ctx.report
+        .set("dbsnp_match_by_chr", serde_json::Value::Object(by_chr));
+}
+
+/// Fetches the reference sequence of each `chrN:start-end` region with
+/// `samtools faidx`, in parallel chunks, uppercased and None where samtools
+/// returned nothing
+fn faidx(ctx: &Ctx, inputs: &[String]) -> Result<Vec<Option<String>>, PipelineError> {
+    let num_inputs = inputs.len();
+    let num_threads = if ctx.args.deterministic {
+        1
+    } else {
+        ctx.args
+            .samtools_threads
+            .unwrap_or_else(|| num_cpus::get() * 4)
+    };
+    let sequences = Mutex::new(vec![None; num_inputs]);
+    let chunk_size = ctx.args.samtools_chunk_size.unwrap_or(5000);
+    let chunks = num_inputs.div_ceil(chunk_size);
+    let chunks = Mutex::new((0..chunks).collect::<Vec<_>>());
+    let failed = Mutex::new(None);
+    debug!(
+        num_threads,
+        num_inputs,
+        chunk_size,
+        chunks = chunks.lock().unwrap().len(),
+        "Running samtools"
+    );
+    std::thread::scope(|s| {
+        for _ in 0..num_threads {
+            s.spawn(|| {
+                loop {
+                    let chunk = {
+                        let mut chunks = chunks.lock().unwrap();
+                        if chunks.is_empty() {
+                            return;
+                        }
+                        chunks.pop().unwrap()
+                    };
+                    let j = chunk * chunk_size;
+                    let end = (j + chunk_size).min(num_inputs);
+                    let input = &inputs[j..end];
+                    debug!(chunk, "Got input");
+                    let mut cmd = std::process::Command::new(&ctx.args.samtools);
+                    cmd.arg("faidx");
+                    cmd.arg(&ctx.args.fasta_ref);
+                    for i in input {
+                        cmd.arg(i);
+                    }
+                    debug!(chunk, "Constructed samtools command");
+                    let output = match cmd.output() {
+                        Ok(o) => o,
+                        Err(e) if e.kind() == std::io::ErrorKind::OutOfMemory => {
+                            chunks.lock().unwrap().push(chunk);
+                            return;
+                        },
+                        Err(e) => {
+                            // stop every worker, the remaining chunks would fail the same way
+                            chunks.lock().unwrap().clear();
+                            failed.lock().unwrap().get_or_insert(e);
+                            return;
+                        },
+                    };
+                    debug!(chunk, "Ran samtools");
+                    let output = String::from_utf8(output.stdout).unwrap();
+                    // key the sequences by the region in each header rather than relying on
+                    // samtools emitting records in the order they were requested
+                    let mut by_region = HashMap::new();
+                    let mut region = None;
+                    for l in output.lines() {
+                        if let Some(r) = l.strip_prefix('>') {
+                            region = Some(r);
+                            by_region.insert(r, String::new());
+                        } else if let Some(r) = region {
+                            by_region.get_mut(r).unwrap().push_str(l);
+                        }
+                    }
+                    let mut sequences = sequences.lock().unwrap();
+                    for (idx, i) in input.iter().enumerate() {
+                        match by_region.get(i.as_str()) {
+                            Some(n) => sequences[idx + j] = Some(n.to_uppercase()),
+                            None => debug!(region = i, "samtools did not return a sequence"),
+                        }
+                    }
+                    debug!(chunk, "Finished samtools");
+                }
+            });
+        }
+    });
+    if let Some(e) = failed.into_inner().unwrap() {
+        return Err(PipelineError::Samtools(e));
+    }
+    debug!("Finished samtools");
+    Ok(sequences.into_inner().unwrap())
+}
+
+/// Compares the ref allele of every indel with the reference sequence it
+/// spans on hg38.
+fn validate_indel_refs(
+    ctx: &Ctx,
+    data: &mut Data,
+    mode: IndelRefCheck,
+) -> Result<(), PipelineError> {
+    let chr = data.idx("chr_hg38");
+    let pos = data.idx("pos_hg38");
+    let ref_ = data.idx("ref");
+    let alt = data.idx("alt");
+    let indels = data
+        .data
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r[ref_].len() != 1 || r[alt].len() != 1)
+        .filter_map(|(i, r)| {
+            let start = r[pos].parse::<u64>().ok()?;
+            let end = start + r[ref_].len() as u64 - 1;
+            Some((i, format!("chr{}:{}-{}", r[chr], start, end)))
+        })
+        .collect::<Vec<_>>();
+    let regions = indels.iter().map(|(_, x)| x.clone()).collect::<Vec<_>>();
+    let sequences = faidx(ctx, &regions)?;
+    let mut matches = vec![None; data.data.len()];
+    for ((i, _), seq) in indels.iter().zip(sequences) {
+        matches[*i] = seq.map(|seq| seq == data.data[*i][ref_]);
+    }
+    let checked = matches.iter().flatten().count();
+    let mismatched = matches.iter().filter(|x| **x == Some(false)).count();
+    if mismatched > 0 {
+        warn!(
+            checked,
+            mismatched, "Indel ref alleles do not match the reference sequence"
+        );
+    }
+    ctx.report.set_in("indel_ref_check", "checked", checked);
+    ctx.report
+        .set_in("indel_ref_check", "mismatched", mismatched);
+    match mode {
+        IndelRefCheck::Flag => {
+            let col = data.idx_or_push("indel_ref_match");
+            for (r, m) in data.data.iter_mut().zip(matches) {
+                r[col] = m.map_or("NA".to_string(), |x| x.to_string());
+            }
+        },
+        IndelRefCheck::Drop => {
+            let mut matches = matches.into_iter();
+            data.data.retain(|_| matches.next().unwrap() != Some(false));
+        },
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(ctx, raw_data_merged, raw_data_missing))]
+fn ref_alt_check(
+    ctx: &Ctx,
+    mut raw_data_merged: Data,
+    raw_data_missing: Data,
+) -> Result<Data, PipelineError> {
+    let ref_checked = ref_check_missing(ctx, &mut raw_data_merged, raw_data_missing)?;
+    raw_data_merged.data.extend(ref_checked);
+    Ok(raw_data_merged)
+}
+
+/// Tags the dbSNP-matched rows with their source and checks the variants
+/// dbSNP doesn't have against the reference genome, returning the kept ones so
+/// the caller can append or stream them
+fn ref_check_missing(
+    ctx: &Ctx,
+    raw_data_merged: &mut Data,
+    raw_data_missing: Data,
+) -> Result<Vec<Vec<String>>, PipelineError> {
+    let num_merged = raw_data_merged.data.len();
+    let num_missing = raw_data_missing.data.len();
+    ctx.report.set("dbsnp_matched", num_merged);
+    ctx.report.set("dbsnp_missing", num_missing);
+    raw_data_merged.header.push("source".to_string());
+    raw_data_merged
+        .data
+        .par_iter_mut()
+        .for_each(|r| r.push("dbsnp_matched".to_string()));
+    if ctx.args.dbsnp_join == DbsnpJoin::Inner {
+        info!(
+            dropped = num_missing,
+            "Dropping variants not found in dbSNP"
+        );
+        ctx.report.set("dbsnp_join_dropped", num_missing);
+        return Ok(vec![]);
+    }
+    let chr_hg38 = raw_data_missing.idx("chr_hg38");
+    let pos_hg38 = raw_data_missing.idx("pos_hg38");
+    let inputs = raw_data_missing
+        .data
+        .iter()
+        .map(|r| format!("chr{}:{}-{}", r[chr_hg38], r[pos_hg38], r[pos_hg38]))
+        .collect::<Vec<_>>();
+    let nucleotides = faidx(ctx, &inputs)?
+        .into_iter()
+        .map(|x| {
+            x.filter(|n| n.len() == 1)
+                .unwrap_or_else(|| "N".to_string())
+        })
+        .collect::<Vec<_>>();
+    // let mut file = std::fs::File::create("nucleotides.txt.gz").unwrap();
+    // for n in &nucleotides {
+    //     writeln!(file, "{n}").unwrap();
+    // }
+    // drop(file);
+    let allele_cols = AlleleCols::new(raw_data_merged);
+    let harmonization = raw_data_merged.idx("harmonization");
+    let complemented = AtomicUsize::new(0);
+    let ref_checked = raw_data_missing
+        .data
+        .into_par_iter()
+        .zip(nucleotides)
+        .filter_map(|(mut d, n)| {
+            if orient_to_reference(ctx, &allele_cols, harmonization, &mut d, &n)? {
+                complemented.fetch_add(1, Ordering::Relaxed);
+            }
+            d.push("ref_checked".to_string());
+            Some(d)
+        })
+        .collect::<Vec<_>>();
+    let complemented = complemented.into_inner();
+    info!(
+        count = complemented,
+        "Complemented alleles to match the reference genome"
+    );
+    ctx.report.set("ref_strand_flipped", complemented);
+    let num_ref_checked = ref_checked.len();
+    info!(
+        kept = num_ref_checked,
+        dropped = num_missing - num_ref_checked,
+        "Joined variants not found in dbSNP"
+    );
+    ctx.report.set("ref_checked_kept", num_ref_checked);
+    ctx.report
+        .set("dbsnp_join_dropped", num_missing - num_ref_checked);
+    Ok(ref_checked)
+}
+
+/// Orients a variant dbSNP doesn't have to the reference base `n`, swapping the
+/// alleles when the alt is the reference and complementing them when they are
+/// reported on the other strand. Returns whether the alleles were complemented,
+/// or None when neither allele matches on either strand.
+fn orient_to_reference(
+    ctx: &Ctx,
+    allele_cols: &AlleleCols,
+    harmonization: usize,
+    d: &mut [String],
+    n: &str,
+) -> Option<bool> {
+    let (ref_, alt) = (allele_cols.ref_, allele_cols.alt);
+    if d[alt] == n {
+        allele_cols.flip(ctx, d);
+        d[harmonization] = "ref_flipped".to_string();
+        return Some(false);
+    }
+    if d[ref_] == n {
+        return Some(false);
+    }
+    let c_ref = complement(&d[ref_])?;
+    let c_alt = complement(&d[alt])?;
+    if c_alt == n {
+        d[ref_] = c_ref.to_string();
+        d[alt] = c_alt.to_string();
+        allele_cols.flip(ctx, d);
+        d[harmonization] = "strand_ref_flipped".to_string();
+    } else if c_ref == n {
+        d[ref_] = c_ref.to_string();
+        d[alt] = c_alt.to_string();
+        d[harmonization] = "strand_flipped".to_string();
+    } else {
+        return None;
+    }
+    Some(true)
+}
+
+/// Writes the audit trail for --flipped-output. The pre-harmonization alleles
+/// and effect are recovered from the harmonization tag: swaps negate the effect
+/// and strand flips complement both alleles.
+fn write_flipped(ctx: &Ctx, data: &Data) {
+    let cols = [
+        "unique_id",
+        "rsid",
+        "chr_hg19",
+        "pos_hg19",
+        "chr_hg38",
+        "pos_hg38",
+        "harmonization",
+    ]
+    .map(|x| data.idx(x));
+    let harmonization = data.idx("harmonization");
+    let allele_cols = AlleleCols::new(data);
+    let mut header = cols
+        .iter()
+        .map(|i| data.header[*i].as_str())
+        .collect::<Vec<_>>();
+    header.extend([
+        "ref_before",
+        "alt_before",
+        "ref_after",
+        "alt_after",
+        "effect_size_before",
+        "effect_size_after",
+    ]);
+    let negated = |effect: &str| {
+        match effect.parse::<f64>() {
+            Ok(e) if effect != "NA" => ctx.fmt_f64(-e),
+            _ => effect.to_string(),
+        }
+    };
+    // the audit rows are built as they are written rather than held alongside
+    // the final data
+    let rows = data.data.iter().filter_map(|r| {
+        let (ref_before, alt_before, effect_before) = match r[harmonization].as_str() {
+            "flipped" | "ref_flipped" | "rsid_flipped" => {
+                (
+                    r[allele_cols.alt].clone(),
+                    r[allele_cols.ref_].clone(),
+                    negated(&r[allele_cols.effect_size]),
+                )
+            },
+            "palindromic_flipped" => {
+                (
+                    r[allele_cols.ref_].clone(),
+                    r[allele_cols.alt].clone(),
+                    negated(&r[allele_cols.effect_size]),
+                )
+            },
+            "strand_flipped" => {
+                (
+                    complement(&r[allele_cols.ref_])?.to_string(),
+                    complement(&r[allele_cols.alt])?.to_string(),
+                    r[allele_cols.effect_size].clone(),
+                )
+            },
+            "strand_ref_flipped" => {
+                (
+                    complement(&r[allele_cols.alt])?.to_string(),
+                    complement(&r[allele_cols.ref_])?.to_string(),
+                    negated(&r[allele_cols.effect_size]),
+                )
+            },
+            _ => return None,
+        };
+        let mut row = cols.iter().map(|i| r[*i].clone()).collect::<Vec<_>>();
+        row.extend([
+            ref_before,
+            alt_before,
+            r[allele_cols.ref_].clone(),
+            r[allele_cols.alt].clone(),
+            effect_before,
+            r[allele_cols.effect_size].clone(),
+        ]);
+        Some(row)
+    });
+    let path = ctx.output_sibling("flipped.txt.gz");
+    info!(path, "Writing flipped variants");
+    let count = Data::write_rows(&path, ctx.compress_threads(), &header, &[], rows);
+    debug!(count, "Wrote flipped variants");
+    ctx.report.set("flipped_output", count);
+}
+
+/// Converts per-SD effects to raw units. This assumes a continuous trait whose
+/// SD is the same across the study, so beta and se scale together and the
+/// p-values are unchanged. Variants with an effect get `effect_rescaled`.
+fn rescale_effects(
+    ctx: &Ctx,
+    data: &mut Data,
+    effect_type: EffectType,
+    trait_sd: f64,
+) -> Result<(), PipelineError> {
+    if effect_type != EffectType::Beta {
+        return Err(PipelineError::EffectUnitsNotBeta);
+    }
+    let effect_size = data.idx("effect_size");
+    let standard_error = data.idx("standard_error");
+    let rescaled_col = data.idx_or_push("effect_rescaled");
+    let rescaled = data
+        .data
+        .par_iter_mut()
+        .map(|r| {
+            let Ok(beta) = r[effect_size].parse::<f64>() else {
+                r[rescaled_col] = "false".to_string();
+                return 0;
+            };
+            r[effect_size] = ctx.fmt_f64(beta * trait_sd);
+            if let Ok(se) = r[standard_error].parse::<f64>() {
+                r[standard_error] = ctx.fmt_f64(se * trait_sd);
+            }
+            r[rescaled_col] = "true".to_string();
+            1
+        })
+        .sum::<usize>();
+    info!(rescaled, trait_sd, "Rescaled effects from SD to raw units");
+    ctx.report.set_in("effect_rescaling", "trait_sd", trait_sd);
+    ctx.report.set_in("effect_rescaling", "rescaled", rescaled);
+    Ok(())
+}
+
+fn filter_min_n(ctx: &Ctx, data: &mut Data, min_n: f64) {
+    let n_total = data.idx("N_total");
+    let before = data.data.len();
+    data.data.retain(|r| {
+        match r[n_total].parse::<f64>() {
+            Ok(n) if !n.is_nan() => n >= min_n,
+            _ => !ctx.args.require_n,
+        }
+    });
+    let dropped = before - data.data.len();
+    info!(
+        dropped,
+        min_n, "Removed variants below the minimum sample size"
     );
-    (raw_data_merged, raw_data_missing)
+    ctx.report.set("min_n_dropped", dropped);
 }
 
-#[tracing::instrument(skip(ctx, raw_data_merged, raw_data_missing))]
-fn ref_alt_check(ctx: &Ctx, mut raw_data_merged: Data, raw_data_missing: Data) -> Data {
-    let chr_hg38 = raw_data_missing.idx("chr_hg38");
-    let pos_hg38 = raw_data_missing.idx("pos_hg38");
-    let inputs = raw_data_missing
+/// SE-N-MAF check: for a standardized continuous trait the standard error is
+/// about 1/sqrt(2 N eaf (1 - eaf)). The reported/expected ratio is scaled by
+/// its median to absorb the phenotype's variance, and variants off by more
+/// than `factor` in either direction are flagged
+fn se_check(ctx: &Ctx, data: &mut Data, factor: f64) {
+    let se = data.idx("standard_error");
+    let eaf = data.idx("EAF");
+    let n_total = data.idx("N_total");
+    let n_case = data.idx("N_case");
+    let ratios = data
+        .data
+        .par_iter()
+        .map(|r| {
+            if r[n_case] != "NA" {
+                return None;
+            }
+            let se = r[se].parse::<f64>().ok()?;
+            let eaf = r[eaf].parse::<f64>().ok()?;
+            let n = r[n_total].parse::<f64>().ok()?;
+            let expected = 1.0 / (2.0 * n * eaf * (1.0 - eaf)).sqrt();
+            let ratio = se / expected;
+            (ratio.is_finite() && ratio > 0.0).then_some(ratio)
+        })
+        .collect::<Vec<_>>();
+    let mut sorted = ratios.iter().flatten().copied().collect::<Vec<_>>();
+    let se_anomalous = data.idx_or_push("se_anomalous");
+    if sorted.is_empty() {
+        info!("No continuous-trait variants with SE, EAF, and N to check");
+        ctx.report.set_in("se_check", "checked", 0);
+        return;
+    }
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let anomalous = data
+        .data
+        .par_iter_mut()
+        .zip(ratios)
+        .map(|(r, ratio)| {
+            let Some(ratio) = ratio else {
+                return 0;
+            };
+            let scaled = ratio / median;
+            let flagged = scaled > factor || scaled < 1.0 / factor;
+            r[se_anomalous] = flagged.to_string();
+            flagged as usize
+        })
+        .sum::<usize>();
+    if anomalous > 0 {
+        warn!(
+            anomalous,
+            median, "Variants with standard errors inconsistent with N and EAF"
+        );
+    }
+    ctx.report.set_in("se_check", "checked", sorted.len());
+    ctx.report.set_in("se_check", "median_ratio", median);
+    ctx.report.set_in("se_check", "anomalous", anomalous);
+}
+
+fn exclude_mhc(ctx: &Ctx, data: &mut Data) {
+    // the extended MHC, chr6:25-35 Mb on hg19 and its lifted coordinates on hg38
+    let (build, start, end) = match ctx.args.primary_build.unwrap_or(Build::Hg38) {
+        Build::Hg19 => ("hg19", 25_000_000, 35_000_000),
+        Build::Hg38 => ("hg38", 24_999_772, 35_032_223),
+    };
+    let chr = data.idx(&format!("chr_{}", build));
+    let pos = data.idx(&format!("pos_{}", build));
+    let before = data.data.len();
+    data.data.retain(|r| {
+        r[chr] != "6"
+            || !r[pos]
+                .parse::<u64>()
+                .is_ok_and(|p| (start..=end).contains(&p))
+    });
+    let excluded = before - data.data.len();
+    info!(excluded, build, "Excluded variants in the MHC");
+    ctx.report.set("mhc_excluded", excluded);
+}
+
+fn harmonize_to_target(
+    ctx: &Ctx,
+    data: &mut Data,
+    target_alleles: &str,
+) -> Result<(), PipelineError> {
+    let file = std::fs::File::open(target_alleles)?;
+    let raw = if target_alleles.ends_with(".gz") {
+        std::io::read_to_string(flate2::read::GzDecoder::new(file))?
+    } else {
+        std::io::read_to_string(file)?
+    };
+    let targets = raw
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let fields = l.split([':', '\t', ' ']).collect::<Vec<_>>();
+            if fields.len() != 4 {
+                return Err(PipelineError::InvalidTargetAllele(l.to_string()));
+            }
+            let chr = normalize_chr(fields[0]).unwrap_or(fields[0]);
+            Ok((chr, fields[1], fields[2], fields[3]))
+        })
+        .collect::<Result<HashSet<_>, _>>()?;
+    let build = match ctx.args.target_build {
+        Build::Hg19 => "hg19",
+        Build::Hg38 => "hg38",
+    };
+    let chr = data.idx(&format!("chr_{}", build));
+    let pos = data.idx(&format!("pos_{}", build));
+    let chr_hg19 = data.idx("chr_hg19");
+    let pos_hg19 = data.idx("pos_hg19");
+    let unique_id = data.idx("unique_id");
+    let harmonization = data.idx("harmonization");
+    let allele_cols = AlleleCols::new(data);
+    let flipped = AtomicUsize::new(0);
+    let missing = AtomicUsize::new(0);
+    data.data.par_iter_mut().for_each(|r| {
+        let key = (
+            r[chr].as_str(),
+            r[pos].as_str(),
+            r[allele_cols.ref_].as_str(),
+            r[allele_cols.alt].as_str(),
+        );
+        if targets.contains(&key) {
+            return;
+        }
+        if !targets.contains(&(key.0, key.1, key.3, key.2)) {
+            missing.fetch_add(1, Ordering::Relaxed);
+            r[harmonization] = "target_missing".to_string();
+            return;
+        }
+        allele_cols.flip(ctx, r);
+        r[unique_id] = format!(
+            "{}_{}_{}_{}",
+            r[chr_hg19], r[pos_hg19], r[allele_cols.ref_], r[allele_cols.alt]
+        );
+        r[harmonization] = "target_flipped".to_string();
+        flipped.fetch_add(1, Ordering::Relaxed);
+    });
+    let flipped = flipped.into_inner();
+    let missing = missing.into_inner();
+    info!(flipped, missing, "Harmonized to target alleles");
+    ctx.report.set("target_flipped", flipped);
+    ctx.report.set("target_missing", missing);
+    if ctx.args.restrict_to_target {
+        data.data.retain(|r| r[harmonization] != "target_missing");
+    }
+    Ok(())
+}
+
+/// Maps the harmonized columns to the GWAS Catalog harmonized format on hg38.
+/// `hm_code` can't be populated from what we track and is left blank, as are
+/// the odds ratio and its confidence interval unless the study reported odds
+/// ratios.
+fn gwas_catalog(ctx: &Ctx, data: &Data, effect_type: EffectType) -> Data {
+    let rsid = data.idx("rsid");
+    let chr = data.idx("chr_hg38");
+    let pos = data.idx("pos_hg38");
+    let ref_ = data.idx("ref");
+    let alt = data.idx("alt");
+    let effect_size = data.idx("effect_size");
+    let standard_error = data.idx("standard_error");
+    let eaf = data.idx("EAF");
+    let pvalue = data.idx("pvalue");
+    let n_total = data.idx("N_total");
+    let header = [
+        "hm_variant_id",
+        "hm_rsid",
+        "hm_chrom",
+        "hm_pos",
+        "hm_other_allele",
+        "hm_effect_allele",
+        "hm_beta",
+        "hm_odds_ratio",
+        "hm_ci_lower",
+        "hm_ci_upper",
+        "hm_effect_allele_frequency",
+        "hm_code",
+        "p_value",
+        "standard_error",
+        "n",
+    ];
+    let data = data
+        .data
+        .par_iter()
+        .map(|r| {
+            let variant_id = if r[pos] == "NA" {
+                "NA".to_string()
+            } else {
+                format!("{}_{}_{}_{}", r[chr], r[pos], r[ref_], r[alt])
+            };
+            let beta = r[effect_size].parse::<f64>().ok();
+            let se = r[standard_error].parse::<f64>().ok();
+            let fmt = |v: Option<f64>| {
+                if effect_type != EffectType::Or {
+                    return String::new();
+                }
+                v.map_or_else(|| "NA".to_string(), |v| ctx.fmt_f64(v))
+            };
+            vec![
+                variant_id,
+                r[rsid].clone(),
+                r[chr].clone(),
+                r[pos].clone(),
+                r[ref_].clone(),
+                r[alt].clone(),
+                r[effect_size].clone(),
+                fmt(beta.map(f64::exp)),
+                fmt(beta.zip(se).map(|(b, s)| (b - 1.96 * s).exp())),
+                fmt(beta.zip(se).map(|(b, s)| (b + 1.96 * s).exp())),
+                r[eaf].clone(),
+                String::new(),
+                r[pvalue].clone(),
+                r[standard_error].clone(),
+                r[n_total].clone(),
+            ]
+        })
+        .collect();
+    Data {
+        header: header.iter().map(|x| x.to_string()).collect(),
+        data,
+    }
+}
+
+fn fill_eaf(ctx: &Ctx, data: &mut Data) -> Result<(), PipelineError> {
+    if let Some(weights) = &ctx.args.ancestry_weights {
+        return fill_eaf_weighted(ctx, data, weights);
+    }
+    let (pop, overwrite) = match &ctx.args.eaf_fill {
+        EafFill::Study => return Ok(()),
+        EafFill::Gnomad(pop) => (Some(pop), true),
+        EafFill::StudyThenGnomad(pop) => (pop.as_ref().or(ctx.args.primary_af.as_ref()), false),
+    };
+    let Some(pop) = pop else {
+        return Err(PipelineError::EafFillPopulationMissing);
+    };
+    let col = format!("gnomAD_AF_{}", pop);
+    let Some(af) = data.idx_opt(&col) else {
+        return Err(PipelineError::AfColumnMissing {
+            column: col,
+            option: "--eaf-fill",
+        });
+    };
+    let eaf = data.idx("EAF");
+    let eaf_source = data.idx_or_push("EAF_source");
+    let filled = data
         .data
+        .par_iter_mut()
+        .map(|r| {
+            let missing = r[eaf] == "NA" || r[eaf] == "NaN";
+            if (overwrite || missing) && r[af] != "NA" {
+                r[eaf] = r[af].clone();
+                r[eaf_source] = "gnomad".to_string();
+                1
+            } else {
+                r[eaf_source] = if missing { "NA" } else { "study" }.to_string();
+                0
+            }
+        })
+        .sum::<usize>();
+    info!(filled, col, "Filled EAF from gnomAD");
+    ctx.report.set("eaf_filled", filled);
+    Ok(())
+}
+
+/// Fills missing study EAF with the average of the gnomAD populations weighted
+/// by the study's ancestry composition, renormalizing over the populations that
+/// have a frequency for the variant
+fn fill_eaf_weighted(
+    ctx: &Ctx,
+    data: &mut Data,
+    weights: &[(String, f64)],
+) -> Result<(), PipelineError> {
+    let pops = weights
         .iter()
-        .map(|r| format!("chr{}:{}-{}", r[chr_hg38], r[pos_hg38], r[pos_hg38]))
+        .map(|(pop, w)| {
+            let col = format!("gnomAD_AF_{}", pop);
+            let Some(af) = data.idx_opt(&col) else {
+                return Err(PipelineError::AfColumnMissing {
+                    column: col,
+                    option: "--ancestry-weights",
+                });
+            };
+            Ok((af, *w))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let eaf = data.idx("EAF");
+    let eaf_source = data.idx_or_push("EAF_source");
+    let filled = data
+        .data
+        .par_iter_mut()
+        .map(|r| {
+            if r[eaf] != "NA" && r[eaf] != "NaN" {
+                r[eaf_source] = "study".to_string();
+                return 0;
+            }
+            let (sum, total) = pops
+                .iter()
+                .filter_map(|&(af, w)| Some((r[af].parse::<f64>().ok()?, w)))
+                .fold((0.0, 0.0), |(sum, total), (af, w)| {
+                    (sum + af * w, total + w)
+                });
+            if total > 0.0 {
+                r[eaf] = ctx.fmt_f64(sum / total);
+                r[eaf_source] = "gnomad_weighted".to_string();
+                1
+            } else {
+                r[eaf_source] = "NA".to_string();
+                0
+            }
+        })
+        .sum::<usize>();
+    info!(filled, "Filled EAF from ancestry-weighted gnomAD");
+    ctx.report.set("eaf_filled", filled);
+    Ok(())
+}
+
+/// Checks every row still has distinct, non-empty ref and alt alleles after
+/// all of the flips, so a bad swap fails here instead of in the output. The
+/// columns are looked up in `data`'s header.
+fn validate_alleles<'a>(
+    data: &Data,
+    rows: impl IntoIterator<Item = &'a Vec<String>>,
+) -> Result<(), PipelineError> {
+    let ref_ = data.idx("ref");
+    let alt = data.idx("alt");
+    let unique_id = data.idx("unique_id");
+    let violator = rows
+        .into_iter()
+        .enumerate()
+        .find(|(_, r)| r[ref_].is_empty() || r[alt].is_empty() || r[ref_] == r[alt]);
+    if let Some((i, r)) = violator {
+        return Err(PipelineError::InconsistentAlleles {
+            row:       i,
+            unique_id: r[unique_id].clone(),
+            ref_:      r[ref_].clone(),
+            alt:       r[alt].clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Builds a PLINK `--score` file, dropping variants without an identifier or
+/// effect size
+fn score_file(ctx: &Ctx, data: &Data) -> Data {
+    let build = match ctx.args.primary_build.unwrap_or(Build::Hg38) {
+        Build::Hg19 => "hg19",
+        Build::Hg38 => "hg38",
+    };
+    let rsid = data.idx("rsid");
+    let chr = data.idx(&format!("chr_{}", build));
+    let pos = data.idx(&format!("pos_{}", build));
+    let ref_ = data.idx("ref");
+    let alt = data.idx("alt");
+    let effect_size = data.idx("effect_size");
+    let rows = data
+        .data
+        .par_iter()
+        .filter_map(|r| {
+            let id = match ctx.args.score_id {
+                ScoreId::Rsid => r[rsid].clone(),
+                ScoreId::Variant if r[pos] == "NA" => "NA".to_string(),
+                ScoreId::Variant => format!("{}:{}:{}:{}", r[chr], r[pos], r[ref_], r[alt]),
+            };
+            if id == "NA" || is_na_effect(&r[effect_size]) {
+                return None;
+            }
+            Some(vec![
+                id,
+                r[alt].clone(),
+                r[ref_].clone(),
+                r[effect_size].clone(),
+            ])
+        })
         .collect::<Vec<_>>();
-    let num_inputs = inputs.len();
-    let num_threads = ctx
-        .args
-        .samtools_threads
-        .unwrap_or_else(|| num_cpus::get() * 4);
-    let nucleotides = Mutex::new(Vec::with_capacity(num_inputs));
-    nucleotides
-        .lock()
-        .unwrap()
-        .extend((0..num_inputs).map(|_| MaybeUninit::uninit()));
-    let chunk_size = ctx.args.samtools_chunk_size.unwrap_or(5000);
-    let chunks = (num_inputs + chunk_size - 1) / chunk_size;
-    let chunks = Mutex::new((0..chunks).collect::<Vec<_>>());
-    debug!(
-        num_threads,
-        num_inputs,
-        chunk_size,
-        chunks = chunks.lock().unwrap().len(),
-        "Running samtools"
-    );
-    std::thread::scope(|s| {
-        for _ in 0..num_threads {
-            s.spawn(|| {
-                loop {
-                    let chunk = {
-                        let mut chunks = chunks.lock().unwrap();
-                        if chunks.is_empty() {
-                            return;
-                        }
-                        chunks.pop().unwrap()
-                    };
-                    let j = chunk * chunk_size;
-                    let end = (j + chunk_size).min(num_inputs);
-                    let input = &inputs[j..end];
-                    debug!(chunk, "Got input");
-                    let mut cmd = std::process::Command::new(&ctx.args.samtools);
-                    cmd.arg("faidx");
-                    cmd.arg(&ctx.args.fasta_ref);
-                    for i in input {
-                        cmd.arg(i);
-                    }
-                    debug!(chunk, "Constructed samtools command");
-                    let output = match cmd.output() {
-                        Ok(o) => o,
-                        Err(e) if e.kind() == std::io::ErrorKind::OutOfMemory => {
-                            chunks.lock().unwrap().push(chunk);
-                            return;
-                        },
-                        Err(e) => {
-                            error!(chunk, ?e, "Failed to run samtools");
-                            return;
-                        },
-                    };
-                    debug!(chunk, "Ran samtools");
-                    let output = String::from_utf8(output.stdout).unwrap();
-                    let mut nucleotides = nucleotides.lock().unwrap();
-                    for (idx, l) in output.lines().filter(|x| !x.starts_with('>')).enumerate() {
-                        nucleotides[idx + j].write(if l.len() > 1 {
-                            "N".to_string()
-                        } else {
-                            l.to_uppercase()
-                        });
-                    }
-                    debug!(chunk, "Finished samtools");
+    let dropped = data.data.len() - rows.len();
+    info!(dropped, "Dropped variants missing score fields");
+    ctx.report.set("score_dropped", dropped);
+    Data {
+        header: ["SNP", "A1", "A2", "effect"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        data:   rows,
+    }
+}
+
+/// Formats the chromosome columns for --chr-output. The pipeline keeps them
+/// canonical (bare, X/Y/M), so this only runs right before writing.
+fn format_chr_output(ctx: &Ctx, data: &mut Data) {
+    if ctx.args.chr_output == ChrOutput::Bare {
+        return;
+    }
+    let cols = chr_columns(&data.header);
+    data.data
+        .par_iter_mut()
+        .for_each(|r| format_chr_row(ctx, &cols, r));
+}
+
+fn chr_columns(header: &[String]) -> Vec<usize> {
+    header
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| *x == "chr" || x.starts_with("chr_"))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn format_chr_row(ctx: &Ctx, cols: &[usize], r: &mut [String]) {
+    for &i in cols {
+        if r[i] == "NA" {
+            continue;
+        }
+        r[i] = match ctx.args.chr_output {
+            ChrOutput::Bare => return,
+            ChrOutput::ChrPrefix => format!("chr{}", r[i]),
+            ChrOutput::Numeric => {
+                match r[i].as_str() {
+                    "X" => "23".to_string(),
+                    "Y" => "24".to_string(),
+                    "M" => "25".to_string(),
+                    _ => continue,
                 }
-            });
+            },
+        };
+    }
+}
+
+/// Adds the derived output columns and returns the columns to write, in order
+fn output_columns(ctx: &Ctx, data: &mut Data) -> Result<Vec<String>, PipelineError> {
+    if let Some(idx) = primary_af_idx(ctx, data)? {
+        data.header.push("AF".to_string());
+        data.data.par_iter_mut().for_each(|r| {
+            let af = r[idx].clone();
+            r.push(af);
+        });
+    }
+    if let Some(build) = ctx.args.primary_build {
+        let (primary, secondary) = match build {
+            Build::Hg19 => ("hg19", "hg38"),
+            Build::Hg38 => ("hg38", "hg19"),
+        };
+        data.rename(&format!("chr_{}", primary), "chr");
+        data.rename(&format!("pos_{}", primary), "pos");
+        data.rename(&format!("chr_{}", secondary), "chr_other");
+        data.rename(&format!("pos_{}", secondary), "pos_other");
+    }
+    Ok(data
+        .header
+        .iter()
+        .filter(|x| !(ctx.args.drop_secondary_build && (*x == "chr_other" || *x == "pos_other")))
+        .filter(|x| *x != "harmonization" || ctx.args.harmonization_column)
+        .filter(|x| *x != "source" || ctx.args.source_column)
+        .filter(|x| !(ctx.args.drop_ancestry_af && ctx.args.af_columns.contains(x)))
+        .cloned()
+        .collect::<Vec<_>>())
+}
+
+/// The gnomAD column copied to `AF` for --primary-af
+fn primary_af_idx(ctx: &Ctx, data: &Data) -> Result<Option<usize>, PipelineError> {
+    let Some(pop) = &ctx.args.primary_af else {
+        return Ok(None);
+    };
+    let col = format!("gnomAD_AF_{}", pop);
+    match data.idx_opt(&col) {
+        Some(idx) => Ok(Some(idx)),
+        None => {
+            Err(PipelineError::AfColumnMissing {
+                column: col,
+                option: "--primary-af",
+            })
+        },
+    }
+}
+
+#[tracing::instrument(skip(ctx, data))]
+fn write_plot_subset(ctx: &Ctx, data: &Data, header: &[&str], p_threshold: f64, sample_n: usize) {
+    let pvalue = data.idx("pvalue");
+    let mut significant = vec![false; data.data.len()];
+    let mut others = vec![];
+    for (i, r) in data.data.iter().enumerate() {
+        match r[pvalue].parse::<f64>() {
+            Ok(p) if p < p_threshold => significant[i] = true,
+            Ok(p) if !p.is_nan() => others.push(i),
+            _ => {},
         }
-    });
-    debug!("Finished samtools");
-    let nucleotides: Vec<String> =
-        unsafe { std::mem::transmute(nucleotides.into_inner().unwrap()) };
-    debug!("Flattened nucleotides");
-    // let mut file = std::fs::File::create("nucleotides.txt.gz").unwrap();
-    // for n in &nucleotides {
-    //     writeln!(file, "{n}").unwrap();
-    // }
-    // drop(file);
-    let ref_ = raw_data_merged.idx("ref");
-    let alt = raw_data_merged.idx("alt");
-    let effect_size = raw_data_merged.idx("effect_size");
-    let eaf = raw_data_merged.idx("EAF");
-    raw_data_merged.data.par_extend(
-        raw_data_missing
+    }
+    let mut rng = ctx.rng();
+    let sampled = rand::seq::index::sample(&mut rng, others.len(), sample_n.min(others.len()));
+    let num_significant = significant.iter().filter(|x| **x).count();
+    let num_sampled = sampled.len();
+    let mut keep = significant;
+    for i in sampled {
+        keep[others[i]] = true;
+    }
+    let subset = Data {
+        header: data.header.clone(),
+        data:   data
             .data
-            .into_par_iter()
-            .zip(nucleotides)
-            .filter_map(|(mut d, n)| {
-                if d[alt] == n {
-                    let (one, two) = d.split_at_mut(alt.max(ref_));
-                    let min = alt.min(ref_);
-                    let max = alt.max(ref_) - one.len();
-                    std::mem::swap(&mut one[min], &mut two[max]);
-                    let es = d[effect_size].parse::<f64>().unwrap();
-                    d[effect_size] = (-es).to_string();
-                    if d[eaf] != "NA" && d[eaf] != "NaN" {
-                        let e = d[eaf].parse::<f64>().unwrap();
-                        d[eaf] = (1.0 - e).to_string();
-                    }
-                    Some(d)
-                } else if d[ref_] == n {
-                    Some(d)
-                } else {
-                    None
+            .iter()
+            .zip(keep)
+            .filter(|(_, k)| *k)
+            .map(|(r, _)| r.clone())
+            .collect(),
+    };
+    let path = ctx.output_sibling("plotsub.txt.gz");
+    info!(
+        significant = num_significant,
+        sampled = num_sampled,
+        path,
+        "Writing plot subset"
+    );
+    subset.write_with_header_order(path, ctx.compress_threads(), header, &[]);
+}
+
+fn intermediate_path(ctx: &Ctx, name: &str) -> Option<PathBuf> {
+    let dir = ctx.args.keep_intermediates.as_ref()?;
+    let dir = dir.replace("{trait_name}", &ctx.trait_name);
+    let ext = match ctx.args.intermediate_compression {
+        Compression::Gzip => "txt.gz",
+        Compression::None => "txt",
+    };
+    Some(Path::new(&dir).join(format!("{}.{}", name, ext)))
+}
+
+fn write_intermediate(ctx: &Ctx, data: &Data, name: &str) {
+    let Some(path) = intermediate_path(ctx, name) else {
+        return;
+    };
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    info!(path = %path.to_string_lossy(), "Writing intermediate file");
+    data.write(path, ctx.compress_threads());
+}
+
+/// Loads the merged and missing intermediates written by --keep-intermediates,
+/// checking they have the columns the ref/alt check expects
+fn read_intermediates(ctx: &Ctx, dir: &str) -> Result<(Data, Data), PipelineError> {
+    let dir = dir.replace("{trait_name}", &ctx.trait_name);
+    let read = |name: &str| {
+        let gz = Path::new(&dir).join(format!("{}.txt.gz", name));
+        let path = if gz.exists() {
+            gz
+        } else {
+            Path::new(&dir).join(format!("{}.txt", name))
+        };
+        info!(path = %path.to_string_lossy(), "Reading intermediate file");
+        let Ok(file) = std::fs::File::open(&path) else {
+            return Err(PipelineError::IntermediateMissing(path));
+        };
+        let data = if path.extension().is_some_and(|x| x == "gz") {
+            Data::read('\t', flate2::read::GzDecoder::new(file), true)
+        } else {
+            Data::read('\t', file, true)
+        };
+        let missing = [
+            "rsid",
+            "unique_id",
+            "chr_hg19",
+            "pos_hg19",
+            "ref",
+            "alt",
+            "chr_hg38",
+            "pos_hg38",
+            "harmonization",
+        ]
+        .into_iter()
+        .filter(|x| data.idx_opt(x).is_none())
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(PipelineError::IntermediateColumnsMissing { path, missing });
+        }
+        Ok(data)
+    };
+    let merged = read("raw_data_merged")?;
+    let missing = read("raw_data_missing")?;
+    if merged.header != missing.header {
+        return Err(PipelineError::IntermediateHeaders {
+            merged:  merged.header,
+            missing: missing.header,
+        });
+    }
+    Ok((merged, missing))
+}
+
+fn output_meta(ctx: &Ctx) -> Vec<String> {
+    let Some(meta) = &ctx.args.output_meta else {
+        return vec![];
+    };
+    let build = match ctx.args.primary_build {
+        Some(Build::Hg19) => "hg19",
+        Some(Build::Hg38) => "hg38",
+        None => "hg19,hg38",
+    };
+    let mut lines = vec![
+        format!("trait_name={}", ctx.trait_name),
+        format!("build={}", build),
+        format!("tool_version={}", env!("CARGO_PKG_VERSION")),
+    ];
+    lines.extend(meta.iter().map(|(k, v)| format!("{}={}", k, v)));
+    lines
+}
+
+fn report_file(ctx: &Ctx) -> String {
+    match &ctx.args.report_file {
+        Some(report_file) => report_file.replace("{trait_name}", &ctx.trait_name),
+        None => format!("{}.report.json", ctx.output_file),
+    }
+}
+
+/// With --no-clobber, fails before doing any work if a file this trait would
+/// write already exists
+fn check_clobber(ctx: &Ctx) -> Result<(), PipelineError> {
+    if !ctx.args.no_clobber {
+        return Ok(());
+    }
+    let mut paths = vec![PathBuf::from(report_file(ctx))];
+    if !ctx.args.summary_only {
+        paths.push(PathBuf::from(&ctx.output_file));
+    }
+    if ctx.args.plot_subset.is_some() {
+        paths.push(ctx.output_sibling("plotsub.txt.gz").into());
+    }
+    if ctx.args.flipped_output {
+        paths.push(ctx.output_sibling("flipped.txt.gz").into());
+    }
+    if ctx.args.keep_failed_parse_rows {
+        paths.push(ctx.output_sibling("parse_errors.txt.gz").into());
+    }
+    paths.extend(unlifted_output(ctx).map(PathBuf::from));
+    for name in ["raw_data", "raw_data_merged", "raw_data_missing"] {
+        paths.extend(intermediate_path(ctx, name));
+    }
+    let existing = paths
+        .iter()
+        .filter(|x| x.exists())
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    if !existing.is_empty() {
+        return Err(PipelineError::OutputExists(existing));
+    }
+    Ok(())
+}
+
+fn eaf_histogram(ctx: &Ctx, data: &Data) {
+    let Some(eaf) = data.idx_opt("EAF") else {
+        warn!("No EAF column, skipping allele frequency histogram");
+        return;
+    };
+    let mut bins = [0usize; 10];
+    let mut out_of_range = 0;
+    let mut na = 0;
+    for r in &data.data {
+        match r[eaf].parse::<f64>() {
+            Ok(v) if v.is_nan() => na += 1,
+            Ok(v) if (0.0..=1.0).contains(&v) => bins[((v * 10.0) as usize).min(9)] += 1,
+            Ok(_) => out_of_range += 1,
+            Err(_) => na += 1,
+        }
+    }
+    for (i, count) in bins.iter().enumerate() {
+        let bin = format!("{:.1}-{:.1}", i as f64 / 10.0, (i + 1) as f64 / 10.0);
+        ctx.report.set_in("eaf_histogram", &bin, *count);
+    }
+    ctx.report
+        .set_in("eaf_histogram", "out_of_range", out_of_range);
+    ctx.report.set_in("eaf_histogram", "NA", na);
+    info!(?bins, out_of_range, na, "Allele frequency histogram");
+    if bins[5..].iter().all(|x| *x == 0) && bins[..5].iter().any(|x| *x != 0) {
+        warn!("No EAF above 0.5, the EAF column may be a minor allele frequency");
+    }
+    if out_of_range > 0 {
+        warn!(
+            out_of_range,
+            "EAF values outside [0, 1], the EAF column may be counts"
+        );
+    }
+}
+
+fn verify_output(
+    ctx: &Ctx,
+    expected_header: &[&str],
+    expected_rows: usize,
+) -> Result<(), PipelineError> {
+    info!("Verifying output");
+    // gzipped only when the name ends in .gz, as in `write_file`
+    let open = || -> Result<Box<dyn std::io::Read>, PipelineError> {
+        let file = std::fs::File::open(&ctx.output_file)?;
+        Ok(if ctx.output_file.ends_with(".gz") {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        })
+    };
+    let (header, rows) = match ctx.args.output_format {
+        OutputFormat::Tsv | OutputFormat::GwasCatalog | OutputFormat::Score => {
+            let mut lines = std::io::BufReader::new(open()?)
+                .lines()
+                .skip_while(|x| x.as_ref().is_ok_and(|x| x.starts_with("##")));
+            let header = match lines.next() {
+                Some(Ok(header)) => header.split('\t').map(|x| x.to_string()).collect(),
+                Some(Err(_)) => return Err(PipelineError::OutputTruncated(0)),
+                None => vec![],
+            };
+            let mut rows = 0;
+            for l in lines {
+                if l.is_err() {
+                    return Err(PipelineError::OutputTruncated(rows));
+                }
+                rows += 1;
+            }
+            (header, rows)
+        },
+        OutputFormat::Ndjson => {
+            let mut keys = None;
+            let mut rows = 0;
+            for l in std::io::BufReader::new(open()?).lines() {
+                let Ok(l) = l else {
+                    return Err(PipelineError::OutputTruncated(rows));
+                };
+                let Ok(serde_json::Value::Object(o)) = serde_json::from_str(&l) else {
+                    return Err(PipelineError::OutputRowNotJson(rows));
+                };
+                keys.get_or_insert_with(|| o.keys().cloned().collect::<Vec<_>>());
+                rows += 1;
+            }
+            // parsed objects don't keep their key order, so compare the key sets
+            let mut expected = expected_header
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>();
+            expected.sort();
+            let header = match keys {
+                Some(keys) if keys != expected => keys,
+                _ => expected_header.iter().map(|x| x.to_string()).collect(),
+            };
+            (header, rows)
+        },
+        OutputFormat::Sqlite => {
+            let conn = rusqlite::Connection::open(&ctx.output_file)?;
+            let table = ctx.sqlite_table();
+            let stmt = conn.prepare(&format!("SELECT * FROM \"{}\" LIMIT 0", table))?;
+            let header = stmt
+                .column_names()
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>();
+            let rows: usize =
+                conn.query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table), [], |r| {
+                    r.get(0)
+                })?;
+            (header, rows)
+        },
+    };
+    if !header.iter().eq(expected_header.iter()) {
+        return Err(PipelineError::OutputHeaderMismatch {
+            expected: expected_header.iter().map(|x| x.to_string()).collect(),
+            found:    header,
+        });
+    }
+    if rows != expected_rows {
+        return Err(PipelineError::OutputRowCount {
+            expected: expected_rows,
+            found:    rows,
+        });
+    }
+    info!(rows, "Verified output");
+    Ok(())
+}
+
+fn timed<T>(ctx: &Ctx, stage: &str, f: impl FnOnce() -> T) -> T {
+    let start = std::time::Instant::now();
+    let res = f();
+    let elapsed = start.elapsed().as_secs_f64();
+    info!(stage, elapsed, "Finished {}", stage);
+    ctx.report.set_in("stage_seconds", stage, elapsed);
+    res
+}
+
+#[tracing::instrument(skip(ctx, data))]
+fn annotate_cytoband(ctx: &Ctx, data: &mut Data, cytoband: &str) -> Result<(), PipelineError> {
+    if !Path::new(cytoband).is_file() {
+        return Err(PipelineError::CytobandMissing(cytoband.into()));
+    }
+    let file = std::fs::File::open(cytoband)?;
+    let bands = if cytoband.ends_with(".gz") {
+        Data::read('\t', flate2::read::GzDecoder::new(file), false)
+    } else {
+        Data::read('\t', file, false)
+    };
+    let bands = bands
+        .data
+        .into_iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let band = || {
+                let [chr, start, end, name, ..] = r.as_slice() else {
+                    return None;
+                };
+                let chr = chr.strip_prefix("chr").unwrap_or(chr).to_string();
+                let band = format!("{}{}", chr, name);
+                Some((
+                    chr,
+                    start.parse::<u64>().ok()?,
+                    end.parse::<u64>().ok()?,
+                    band,
+                ))
+            };
+            band().ok_or_else(|| {
+                PipelineError::InvalidCytoband {
+                    path: cytoband.into(),
+                    line: i + 1,
                 }
-            }),
-    );
-    debug!("Merged missing data");
-    raw_data_merged
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let index = IntervalIndex::new(bands);
+    let chr_hg38 = data.idx("chr_hg38");
+    let pos_hg38 = data.idx("pos_hg38");
+    let na = "NA".to_string();
+    data.header.push("band".to_string());
+    data.data.par_iter_mut().for_each(|r| {
+        let band = r[pos_hg38]
+            .parse::<u64>()
+            .ok()
+            .and_then(|pos| index.find(&r[chr_hg38], pos.saturating_sub(1)).next())
+            .map(|x| &x.2)
+            .unwrap_or(&na)
+            .clone();
+        r.push(band);
+    });
+    let annotated = data.col("band").filter(|x| *x != "NA").count();
+    info!(annotated, "Annotated cytogenetic bands");
+    ctx.report.set("cytoband_annotated", annotated);
+    Ok(())
 }
 
-// potential future improvements:
-// - samtools seems like it still has a lot of CPU headroom to spare
-// - writing out to files is very slow
-// - reading in files is very poorly parallelized, it spends a lot of time
-//   allocating all the Strings
-fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::builder()
-                .with_default_directive(tracing::Level::INFO.into())
-                .from_env_lossy(),
-        )
-        .init();
+/// GETs `url`, retrying network errors and 5xx/429 responses with exponential
+/// backoff. Other statuses are returned straight away for the caller to handle.
+/// Errors have the URL stripped since Sheets API URLs carry the key.
+fn get_with_retries(url: &str, retries: u32) -> reqwest::Result<reqwest::blocking::Response> {
+    let mut attempt = 0;
+    loop {
+        let response = reqwest::blocking::get(url).map_err(|e| e.without_url());
+        let transient = match &response {
+            Ok(response) => {
+                let status = response.status();
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            },
+            Err(_) => true,
+        };
+        if !transient || attempt == retries {
+            return response;
+        }
+        let wait = std::time::Duration::from_secs(1 << attempt.min(6));
+        attempt += 1;
+        match &response {
+            Ok(response) => {
+                warn!(status = %response.status(), attempt, ?wait, "Request failed, retrying")
+            },
+            Err(e) => warn!(?e, attempt, ?wait, "Request failed, retrying"),
+        }
+        std::thread::sleep(wait);
+    }
+}
 
-    let args = Args::parse();
-    if args.google_sheets_id.starts_with("http") {
+/// Fetches a Sheets API response, exiting with the API's own message when it
+/// returns an error object instead of data.
+fn sheets_get(url: &str, retries: u32) -> serde_json::Value {
+    let response = match get_with_retries(url, retries) {
+        Ok(response) => response,
+        Err(e) => {
+            error!(?e, "Failed to reach the Google Sheets API");
+            std::process::exit(1);
+        },
+    };
+    let status = response.status();
+    let body = response.text().unwrap();
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(&body) else {
+        error!(%status, body, "Google Sheets API returned something other than JSON");
+        std::process::exit(1);
+    };
+    if let Some(e) = json.get("error") {
+        let message = e["message"].as_str().unwrap_or("");
+        let api_status = e["status"].as_str().unwrap_or("");
+        let hint = match api_status {
+            "PERMISSION_DENIED" => "the spreadsheet is probably not shared publicly",
+            "NOT_FOUND" => "check the google_sheets_id",
+            "INVALID_ARGUMENT" if message.contains("API key") => "the API key was rejected",
+            _ => "",
+        };
+        error!(
+            http_status = %status,
+            status = api_status,
+            message,
+            hint,
+            "Google Sheets API returned an error"
+        );
+        std::process::exit(1);
+    }
+    if !status.is_success() {
+        error!(%status, body, "Google Sheets API request failed");
+        std::process::exit(1);
+    }
+    json
+}
+
+/// Resolves the Google Sheets API key from GWAS_SHEETS_API_KEY, then
+/// --api-key, then the built-in key.
+fn sheets_api_key(args: &Args) -> String {
+    if let Ok(key) = std::env::var("GWAS_SHEETS_API_KEY") {
+        debug!("Using the Google Sheets API key from GWAS_SHEETS_API_KEY");
+        key
+    } else if let Some(key) = &args.api_key {
+        debug!("Using the Google Sheets API key from --api-key");
+        key.clone()
+    } else {
+        debug!("Using the built-in Google Sheets API key");
+        GOOGLE_SHEETS_API_KEY.to_string()
+    }
+}
+
+fn fetch_sheet(
+    google_sheets_id: &str,
+    api_key: &str,
+    sheet_name: Option<&str>,
+    retries: u32,
+) -> Data {
+    if google_sheets_id.starts_with("http") {
         error!("google_sheets_id should be the ID of the Google Sheets document, not the URL. For example, if the URL is https://docs.google.com/spreadsheets/d/1a2b3c4d5e6f7g8h9i0j1k2l3m4n5o6p7q8r9s0t1u2v3w4x5y6z7/edit#gid=0, the ID is 1a2b3c4d5e6f7g8h9i0j1k2l3m4n5o6p7q8r9s0t1u2v3w4x5y6z7");
-        return;
+        std::process::exit(1);
     }
-    let spreadsheet = reqwest::blocking::get(format!(
-        "https://sheets.googleapis.com/v4/spreadsheets/{}?key={}",
-        args.google_sheets_id, GOOGLE_SHEETS_API_KEY
-    ))
-    .unwrap()
-    .error_for_status()
-    .unwrap();
-    let spreadsheet = spreadsheet.text().unwrap();
-    let spreadsheet: serde_json::Value = serde_json::from_str(&spreadsheet).unwrap();
-    let spreadsheet = spreadsheet["sheets"].as_array().unwrap()[0]["properties"]["title"]
-        .as_str()
-        .unwrap();
-    let data = reqwest::blocking::get(format!(
-        "https://sheets.googleapis.com/v4/spreadsheets/{}/values/{}?key={}",
-        args.google_sheets_id, spreadsheet, GOOGLE_SHEETS_API_KEY
-    ))
-    .unwrap()
-    .error_for_status()
-    .unwrap();
-    let data = data.text().unwrap();
-    let data: serde_json::Value = serde_json::from_str(&data).unwrap();
+    let spreadsheet = sheets_get(
+        &format!(
+            "https://sheets.googleapis.com/v4/spreadsheets/{}?key={}",
+            google_sheets_id, api_key
+        ),
+        retries,
+    );
+    let titles = spreadsheet["sheets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["properties"]["title"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    let spreadsheet = match sheet_name {
+        Some(sheet_name) => {
+            let Some(title) = titles.iter().find(|x| **x == sheet_name) else {
+                error!(sheet_name, tabs = ?titles, "No tab with this name in the spreadsheet");
+                std::process::exit(1);
+            };
+            title
+        },
+        None => titles[0],
+    };
+    let data = sheets_get(
+        &format!(
+            "https://sheets.googleapis.com/v4/spreadsheets/{}/values/{}?key={}",
+            google_sheets_id, spreadsheet, api_key
+        ),
+        retries,
+    );
     let data = data["values"].as_array().unwrap();
     let header = data[0].as_array().unwrap();
     let header = header
@@ -1118,22 +5216,910 @@ fn main() {
                 .collect::<Vec<_>>()
         })
         .collect::<Vec<_>>();
-    let data = Data { header, data };
+    Data { header, data }
+}
+
+fn fetch_legend_url(legend_url: &str, retries: u32) -> Data {
+    info!(legend_url, "Fetching the GWAS formatting legend");
+    let response = match get_with_retries(legend_url, retries) {
+        Ok(response) => response,
+        Err(e) => {
+            error!(legend_url, ?e, "Failed to fetch the legend URL");
+            std::process::exit(1);
+        },
+    };
+    let status = response.status();
+    if !status.is_success() {
+        error!(legend_url, %status, "The legend URL returned an error status");
+        std::process::exit(1);
+    }
+    let legend = match response.text() {
+        Ok(legend) => legend,
+        Err(e) => {
+            error!(legend_url, ?e, "Failed to read the legend URL's response");
+            std::process::exit(1);
+        },
+    };
+    let legend = read_raw_data(',', legend.as_bytes());
+    if legend.idx_opt("trait_name").is_none() {
+        error!(
+            legend_url,
+            header = ?legend.header,
+            "The legend URL does not look like a GWAS formatting legend, it has no trait_name column"
+        );
+        std::process::exit(1);
+    }
+    legend
+}
+
+/// Reads the legend from disk, comma-delimited for `.csv` files and
+/// tab-delimited otherwise.
+fn read_legend_file(legend_file: &str) -> Data {
+    info!(legend_file, "Reading the GWAS formatting legend");
+    let file = match std::fs::File::open(legend_file) {
+        Ok(file) => file,
+        Err(e) => {
+            error!(legend_file, %e, "Failed to open the legend file");
+            std::process::exit(1);
+        },
+    };
+    let name = legend_file.strip_suffix(".gz").unwrap_or(legend_file);
+    let delim = if name.ends_with(".csv") { ',' } else { '\t' };
+    let legend = if legend_file.ends_with(".gz") {
+        read_raw_data(delim, flate2::read::GzDecoder::new(file))
+    } else {
+        read_raw_data(delim, file)
+    };
+    if legend.idx_opt("trait_name").is_none() {
+        error!(
+            legend_file,
+            header = ?legend.header,
+            "The legend file does not look like a GWAS formatting legend, it has no trait_name column"
+        );
+        std::process::exit(1);
+    }
+    legend
+}
+
+// potential future improvements:
+// - samtools seems like it still has a lot of CPU headroom to spare
+// - writing out to files is very slow
+// - reading in files is very poorly parallelized, it spends a lot of time
+//   allocating all the Strings
+fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::builder()
+                .with_default_directive(tracing::Level::INFO.into())
+                .from_env_lossy(),
+        )
+        .init();
+
+    let args = Args::parse();
+    if let Some(threads) = args.deterministic.then_some(1).or(args.threads) {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+    let data = match (&args.legend_file, &args.legend_url) {
+        (Some(legend_file), _) => read_legend_file(legend_file),
+        (None, Some(legend_url)) => fetch_legend_url(legend_url, args.http_retries),
+        (None, None) => {
+            fetch_sheet(
+                args.google_sheets_id.as_ref().unwrap(),
+                &sheets_api_key(&args),
+                args.sheet_name.as_deref(),
+                args.http_retries,
+            )
+        },
+    };
     debug!("Header: {:?}", data.header);
-    let ctx = Ctx { args, sheet: data };
-    info!(trait_name = %ctx.args.trait_name, "Starting pipeline");
-    info!("Starting preformatting");
-    let raw_data = preformat(&ctx);
-    // raw_data.write("raw_data.txt.gz");
-    info!("Starting liftover");
-    liftover(&ctx, &raw_data);
-    info!("Starting dbSNP matching");
-    let (raw_data_merged, raw_data_missing) = dbsnp_matching(&ctx, raw_data);
-    // raw_data_merged.write("raw_data_merged.txt.gz");
-    // raw_data_missing.write("raw_data_missing.txt.gz");
+    if args.trait_name.len() > 1 && !args.output_file.contains("{trait_name}") {
+        error!("--output-file must contain {{trait_name}} when processing multiple traits");
+        std::process::exit(1);
+    }
+    if let Some(batch_summary) = &args.batch_summary {
+        if args.no_clobber && Path::new(batch_summary).exists() {
+            error!(
+                batch_summary,
+                "Batch summary already exists, refusing to overwrite it with --no-clobber"
+            );
+            std::process::exit(1);
+        }
+    }
+    if args.from_intermediates.is_some() && args.stop_after.is_some_and(|x| x != Stage::Refalt) {
+        error!("--from-intermediates starts at the ref/alt check, so --stop-after must be refalt");
+        std::process::exit(1);
+    }
+    let parallel_traits = args.parallel_traits.clamp(1, args.trait_name.len());
+    let mut trait_args = args.clone();
+    if parallel_traits > 1 && trait_args.samtools_threads.is_none() {
+        // share the default samtools thread budget between the concurrent traits
+        trait_args.samtools_threads = Some((num_cpus::get() * 4 / parallel_traits).max(1));
+    }
+    let succeeded = Mutex::new(vec![]);
+    let failed = Mutex::new(serde_json::Map::new());
+    let process = |trait_name: &String| {
+        let ctx = Ctx {
+            args:        trait_args.clone(),
+            sheet:       data.clone(),
+            report:      Report::default(),
+            trait_name:  trait_name.clone(),
+            output_file: args.output_file.replace("{trait_name}", trait_name),
+        };
+        if !args.continue_on_error {
+            if let Err(e) = run_trait(&ctx) {
+                error!(trait_name, "{}", e);
+                std::process::exit(1);
+            }
+            succeeded.lock().unwrap().push(trait_name.clone());
+            return;
+        }
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_trait(&ctx))) {
+            Ok(Ok(())) => succeeded.lock().unwrap().push(trait_name.clone()),
+            Ok(Err(e)) => {
+                error!(trait_name, error = %e, "Failed to process trait, continuing");
+                failed
+                    .lock()
+                    .unwrap()
+                    .insert(trait_name.clone(), e.to_string().into());
+            },
+            Err(e) => {
+                let e = e
+                    .downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                error!(trait_name, error = e, "Failed to process trait, continuing");
+                failed.lock().unwrap().insert(trait_name.clone(), e.into());
+            },
+        }
+    };
+    if parallel_traits == 1 {
+        args.trait_name.iter().for_each(process);
+    } else {
+        info!(parallel_traits, "Processing traits concurrently");
+        let queue = Mutex::new(args.trait_name.iter());
+        std::thread::scope(|s| {
+            for _ in 0..parallel_traits {
+                s.spawn(|| {
+                    loop {
+                        let Some(trait_name) = queue.lock().unwrap().next() else {
+                            return;
+                        };
+                        process(trait_name);
+                    }
+                });
+            }
+        });
+    }
+    let succeeded = succeeded.into_inner().unwrap();
+    let failed = failed.into_inner().unwrap();
+    if let Some(batch_summary) = &args.batch_summary {
+        let file = std::fs::File::create(batch_summary).unwrap();
+        serde_json::to_writer_pretty(
+            file,
+            &serde_json::json!({ "succeeded": succeeded, "failed": failed }),
+        )
+        .unwrap();
+    }
+    if !failed.is_empty() {
+        error!(
+            failed = failed.len(),
+            succeeded = succeeded.len(),
+            traits = ?failed.keys().collect::<Vec<_>>(),
+            "Some traits failed"
+        );
+        std::process::exit(1);
+    }
+}
+
+#[tracing::instrument(skip(ctx), fields(trait_name = %ctx.trait_name))]
+fn run_trait(ctx: &Ctx) -> Result<(), PipelineError> {
+    ctx.report.set("trait_name", ctx.trait_name.as_str());
+    info!(trait_name = %ctx.trait_name, "Starting pipeline");
+    if ctx.args.dump_legend || ctx.args.dry_run {
+        let row = legend_row(ctx)?;
+        if ctx.args.dump_legend {
+            dump_legend(ctx, row)?;
+        }
+        if ctx.args.dry_run {
+            info!("Dry run, stopping after resolving the GWAS formatting legend");
+            return Ok(());
+        }
+    }
+    check_clobber(ctx)?;
+    let (raw_data_merged, raw_data_missing) = if let Some(dir) = &ctx.args.from_intermediates {
+        info!("Loading intermediates, skipping preformatting, liftover, and dbSNP matching");
+        read_intermediates(ctx, dir)?
+    } else {
+        info!("Starting preformatting");
+        let raw_data = timed(ctx, "preformat", || preformat(ctx))?;
+        if raw_data.data.is_empty() {
+            ctx.report.set("empty_input", true);
+            return write_output(ctx, empty_output(ctx));
+        }
+        write_intermediate(ctx, &raw_data, "raw_data");
+        if ctx.args.stop_after == Some(Stage::Preformat) {
+            stop(ctx, Stage::Preformat, &[(&raw_data, "raw_data")]);
+            return Ok(());
+        }
+        info!("Starting liftover");
+        let lifted = timed(ctx, "liftover", || liftover(ctx, &raw_data))?;
+        if ctx.args.stop_after == Some(Stage::Liftover) {
+            stop(ctx, Stage::Liftover, &[(&lifted.to_data(), "lifted")]);
+            return Ok(());
+        }
+        info!("Starting dbSNP matching");
+        let (raw_data_merged, raw_data_missing) = timed(ctx, "dbsnp_matching", || {
+            dbsnp_matching(ctx, raw_data, lifted)
+        })?;
+        write_intermediate(ctx, &raw_data_merged, "raw_data_merged");
+        write_intermediate(ctx, &raw_data_missing, "raw_data_missing");
+        if ctx.args.stop_after == Some(Stage::Dbsnp) {
+            stop(ctx, Stage::Dbsnp, &[
+                (&raw_data_merged, "raw_data_merged"),
+                (&raw_data_missing, "raw_data_missing"),
+            ]);
+            return Ok(());
+        }
+        (raw_data_merged, raw_data_missing)
+    };
     info!("Starting ref/alt check");
-    let final_data = ref_alt_check(&ctx, raw_data_merged, raw_data_missing);
-    info!("Writing final data to {}", ctx.args.output_file);
-    final_data.write(&ctx.args.output_file);
+    if can_stream_output(ctx) {
+        let mut raw_data_merged = raw_data_merged;
+        let ref_checked = timed(ctx, "ref_alt_check", || {
+            ref_check_missing(ctx, &mut raw_data_merged, raw_data_missing)
+        })?;
+        return stream_output(ctx, raw_data_merged, ref_checked);
+    }
+    let mut final_data = timed(ctx, "ref_alt_check", || {
+        ref_alt_check(ctx, raw_data_merged, raw_data_missing)
+    })?;
+    if ctx.args.stop_after == Some(Stage::Refalt) {
+        stop(ctx, Stage::Refalt, &[(&final_data, "ref_alt_checked")]);
+        return Ok(());
+    }
+    if ctx.args.flipped_output {
+        write_flipped(ctx, &final_data);
+    }
+    if let Some(mode) = ctx.args.validate_indel_ref {
+        validate_indel_refs(ctx, &mut final_data, mode)?;
+    }
+    if ctx.args.effect_units == EffectUnits::Sd {
+        let effect_type = effect_type(ctx, legend_row(ctx)?)?;
+        rescale_effects(
+            ctx,
+            &mut final_data,
+            effect_type,
+            ctx.args.trait_sd.unwrap(),
+        )?;
+    }
+    if let Some(target_alleles) = &ctx.args.target_alleles {
+        harmonize_to_target(ctx, &mut final_data, target_alleles)?;
+    }
+    if ctx.args.exclude_mhc {
+        exclude_mhc(ctx, &mut final_data);
+    }
+    if let Some(min_n) = ctx.args.min_n {
+        filter_min_n(ctx, &mut final_data, min_n);
+    }
+    if let Some(cytoband) = &ctx.args.cytoband {
+        annotate_cytoband(ctx, &mut final_data, cytoband)?;
+    }
+    if ctx.args.report_allele_frequency_histogram {
+        eaf_histogram(ctx, &final_data);
+    }
+    if ctx.args.impute_pvalue {
+        impute_pvalues(ctx, &mut final_data, effect_type(ctx, legend_row(ctx)?)?);
+    }
+    fill_eaf(ctx, &mut final_data)?;
+    if let Some(factor) = ctx.args.se_check {
+        se_check(ctx, &mut final_data, factor);
+    }
+    validate_alleles(&final_data, &final_data.data)?;
+    write_output(ctx, final_data)
+}
+
+/// Ends the run early for --stop-after, writing the stage's data and the
+/// report.
+fn stop(ctx: &Ctx, stage: Stage, outputs: &[(&Data, &str)]) {
+    for (data, name) in outputs {
+        let path = intermediate_path(ctx, name)
+            .unwrap_or_else(|| ctx.output_sibling(&format!("{}.txt.gz", name)).into());
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        info!(path = %path.to_string_lossy(), "Writing stage output");
+        data.write(path, ctx.compress_threads());
+    }
+    ctx.report
+        .set("stopped_after", format!("{:?}", stage).to_lowercase());
+    let report_file = report_file(ctx);
+    info!("Writing report to {}", report_file);
+    ctx.report.write(report_file);
+    info!(?stage, "Stopped early");
+}
+
+/// The columns the pipeline produces up to the output step, with no rows, for
+/// --allow-empty
+fn empty_output(ctx: &Ctx) -> Data {
+    let mut header = [
+        "rsid",
+        "unique_id",
+        "chr_hg19",
+        "pos_hg19",
+        "ref",
+        "alt",
+        "effect_size",
+        "standard_error",
+        "EAF",
+        "pvalue",
+        "pvalue_het",
+        "N_total",
+        "N_case",
+        "N_ctrl",
+        "chr_hg38",
+        "pos_hg38",
+    ]
+    .map(String::from)
+    .to_vec();
+    if ctx.args.lift_to == Some(LiftTarget::Chm13) {
+        header.extend(["chr_chm13".to_string(), "pos_chm13".to_string()]);
+    }
+    header.extend(ctx.args.af_columns.iter().cloned());
+    header.push("harmonization".to_string());
+    for col in &ctx.args.keep_columns {
+        if !header.contains(col) {
+            header.push(col.clone());
+        }
+    }
+    header.push("source".to_string());
+    Data {
+        header,
+        data: vec![],
+    }
+}
+
+/// Whether the ref/alt checked rows can go straight to the output, which holds
+/// when none of the later steps needs the whole data set
+fn can_stream_output(ctx: &Ctx) -> bool {
+    let args = &ctx.args;
+    args.stop_after != Some(Stage::Refalt)
+        && !args.flipped_output
+        && args.validate_indel_ref.is_none()
+        && args.effect_units != EffectUnits::Sd
+        && args.target_alleles.is_none()
+        && !args.exclude_mhc
+        && args.min_n.is_none()
+        && args.cytoband.is_none()
+        && !args.report_allele_frequency_histogram
+        && !args.impute_pvalue
+        && args.eaf_fill == EafFill::Study
+        && args.ancestry_weights.is_none()
+        && args.se_check.is_none()
+        && args.output_format == OutputFormat::Tsv
+        && args.plot_subset.is_none()
+        && !args.summary_only
+}
+
+/// Writes the dbSNP-matched rows and then the reference-checked ones straight
+/// to the output, one row at a time, so the final data is never collected
+fn stream_output(
+    ctx: &Ctx,
+    raw_data_merged: Data,
+    ref_checked: Vec<Vec<String>>,
+) -> Result<(), PipelineError> {
+    validate_alleles(
+        &raw_data_merged,
+        raw_data_merged.data.iter().chain(&ref_checked),
+    )?;
+    let af = primary_af_idx(ctx, &raw_data_merged)?;
+    let mut columns = Data {
+        header: raw_data_merged.header,
+        data:   vec![],
+    };
+    let header = output_columns(ctx, &mut columns)?;
+    let idxs = header.iter().map(|x| columns.idx(x)).collect::<Vec<_>>();
+    let chr = chr_columns(&columns.header);
+    let header = header.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    info!("Writing final data to {}", ctx.output_file);
+    let rows = raw_data_merged
+        .data
+        .into_iter()
+        .chain(ref_checked)
+        .map(|mut r| {
+            if let Some(af) = af {
+                r.push(r[af].clone());
+            }
+            format_chr_row(ctx, &chr, &mut r);
+            idxs.iter()
+                .map(|&i| std::mem::take(&mut r[i]))
+                .collect::<Vec<_>>()
+        });
+    let count = Data::write_rows(
+        &ctx.output_file,
+        ctx.compress_threads(),
+        &header,
+        &output_meta(ctx),
+        rows,
+    );
+    ctx.report.set("output_rows", count);
+    if ctx.args.verify_output {
+        verify_output(ctx, &header, count)?;
+    }
+    let report_file = report_file(ctx);
+    info!("Writing report to {}", report_file);
+    ctx.report.write(report_file);
+    info!("Pipeline complete");
+    Ok(())
+}
+
+/// Converts and writes the final data, verifies it, and writes the plot subset
+/// and report.
+fn write_output(ctx: &Ctx, mut final_data: Data) -> Result<(), PipelineError> {
+    let converted = match ctx.args.output_format {
+        OutputFormat::GwasCatalog => {
+            let effect_type = effect_type(ctx, legend_row(ctx)?)?;
+            Some(gwas_catalog(ctx, &final_data, effect_type))
+        },
+        OutputFormat::Score => Some(score_file(ctx, &final_data)),
+        _ => None,
+    };
+    let header = output_columns(ctx, &mut final_data)?;
+    let header = header.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    format_chr_output(ctx, &mut final_data);
+    ctx.report.set(
+        "output_rows",
+        converted.as_ref().unwrap_or(&final_data).data.len(),
+    );
+    if ctx.args.summary_only {
+        info!("Skipping the output with --summary-only");
+    } else {
+        info!("Writing final data to {}", ctx.output_file);
+        let written = match (ctx.args.output_format, &converted) {
+            (_, Some(converted)) => {
+                converted.write(&ctx.output_file, ctx.compress_threads());
+                converted.header.iter().map(|x| x.as_str()).collect()
+            },
+            (OutputFormat::Ndjson, _) => {
+                write_ndjson(
+                    &final_data.select(&header),
+                    &ctx.output_file,
+                    ctx.compress_threads(),
+                );
+                header.clone()
+            },
+            (OutputFormat::Sqlite, _) => {
+                write_sqlite(
+                    &final_data.select(&header),
+                    &ctx.output_file,
+                    ctx.sqlite_table(),
+                );
+                header.clone()
+            },
+            _ => {
+                final_data.write_with_header_order(
+                    &ctx.output_file,
+                    ctx.compress_threads(),
+                    &header,
+                    &output_meta(ctx),
+                );
+                header.clone()
+            },
+        };
+        if ctx.args.verify_output {
+            let rows = converted.as_ref().unwrap_or(&final_data).data.len();
+            verify_output(ctx, &written, rows)?;
+        }
+        if let Some((p_threshold, sample_n)) = ctx.args.plot_subset {
+            write_plot_subset(ctx, &final_data, &header, p_threshold, sample_n);
+        }
+    }
+    let report_file = report_file(ctx);
+    info!("Writing report to {}", report_file);
+    ctx.report.write(report_file);
     info!("Pipeline complete");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(header: &[&str], rows: &[&[&str]]) -> Data {
+        Data {
+            header: header.iter().map(|x| x.to_string()).collect(),
+            data:   rows
+                .iter()
+                .map(|r| r.iter().map(|x| x.to_string()).collect())
+                .collect(),
+        }
+    }
+
+    fn ctx(sheet: Data, args: &[&str]) -> Ctx {
+        let required = [
+            "gwas-summary-stats",
+            "-t",
+            "T1",
+            "--legend-file",
+            "legend.csv",
+            "--liftover-dir",
+            ".",
+            "-r",
+            "x",
+            "-d",
+            "dbsnp.tsv.gz",
+            "-s",
+            "samtools",
+            "-f",
+            "ref.fa",
+            "-o",
+            "out.txt.gz",
+        ];
+        Ctx {
+            args: Args::parse_from(required.iter().chain(args)),
+            sheet,
+            report: Report::default(),
+            trait_name: "T1".to_string(),
+            output_file: "out.txt.gz".to_string(),
+        }
+    }
+
+    /// A legend with a single T1 row, with the given columns set and the
+    /// rest NA or a tab-delimited hg19 default
+    fn legend(cols: &[(&str, &str)]) -> Data {
+        let mut header = vec!["trait_name"];
+        header.extend(COLS_MUST_BE_PRESENT);
+        let row = header
+            .iter()
+            .map(|x| {
+                let default = match *x {
+                    "trait_name" => "T1",
+                    "effect_is_OR" => "N",
+                    "column_delim" => "tab",
+                    "hg_version" => "hg19",
+                    _ => "NA",
+                };
+                cols.iter()
+                    .find(|(c, _)| c == x)
+                    .map_or(default, |(_, v)| *v)
+            })
+            .collect::<Vec<_>>();
+        data(&header, &[&row])
+    }
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gwas-summary-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn write_file_round_trips_plain_and_gz() {
+        let contents = "a\tb\n1\t2\n";
+        let plain = temp_path("round_trip.txt");
+        write_file(&plain, 1, |w| w.write_all(contents.as_bytes()).unwrap());
+        assert_eq!(std::fs::read_to_string(&plain).unwrap(), contents);
+        // the single-threaded and the parallel encoder
+        for threads in [1, 2] {
+            let gz = temp_path(&format!("round_trip_{}.txt.gz", threads));
+            write_file(&gz, threads, |w| w.write_all(contents.as_bytes()).unwrap());
+            let file = std::fs::File::open(&gz).unwrap();
+            let read = std::io::read_to_string(flate2::read::GzDecoder::new(file)).unwrap();
+            assert_eq!(read, contents);
+        }
+    }
+
+    #[test]
+    fn header_collision_keeps_the_legend_column() {
+        let sheet = legend(&[("N_total_column", "N")]);
+        let ctx = ctx(sheet, &[]);
+        let raw_header = strings(&["SNP", "N", "N_total"]);
+        let mut header = strings(&["SNP", "N_total", "N_total"]);
+        resolve_header_collisions(&ctx, &ctx.sheet.data[0], &raw_header, &mut header).unwrap();
+        assert_eq!(header, ["SNP", "N_total", "N_total_raw"]);
+    }
+
+    #[test]
+    fn strict_header_rejects_a_duplicate_n_total() {
+        let sheet = legend(&[("N_total_column", "N")]);
+        let ctx = ctx(sheet, &["--strict-header"]);
+        let raw_header = strings(&["SNP", "N", "N_total"]);
+        let mut header = strings(&["SNP", "N_total", "N_total"]);
+        let result = resolve_header_collisions(&ctx, &ctx.sheet.data[0], &raw_header, &mut header);
+        assert!(matches!(
+            result,
+            Err(PipelineError::HeaderCollision { column, .. }) if column == "N_total"
+        ));
+    }
+
+    #[test]
+    fn normalize_chr_canonicalizes_contig_names() {
+        assert_eq!(normalize_chr("chr1"), Some("1"));
+        assert_eq!(normalize_chr("chrX"), Some("X"));
+        assert_eq!(normalize_chr("23"), Some("X"));
+        assert_eq!(normalize_chr("chrMT"), Some("M"));
+        assert_eq!(normalize_chr("1"), None);
+    }
+
+    #[test]
+    fn dbsnp_with_chr_prefixed_contigs_matches_the_study() {
+        let path = temp_path("chr_prefixed.tsv.gz");
+        write_file(&path, 1, |w| {
+            w.write_all(b"chr\tpos_hg19\tref\talt\tpos_hg38\trsid\n")
+                .unwrap();
+            w.write_all(b"chr1\t100\tA\tG\t1100\trs1\n").unwrap();
+            w.write_all(b"chr23\t200\tC\tT\t1200\trs2\n").unwrap();
+        });
+        let mut ctx = ctx(legend(&[]), &[]);
+        ctx.args.dbsnp_file = path.to_string_lossy().to_string();
+        let raw_data = data(&["chr_hg19", "pos_hg19", "chr_hg38", "pos_hg38"], &[
+            &["1", "100", "1", "1100"],
+            &["X", "200", "X", "1200"],
+        ]);
+        let dbsnp = read_dbsnp(&ctx, &DbsnpFilter::new(&raw_data, None, false)).unwrap();
+        assert_eq!(
+            dbsnp.lookup(["1", "100", "A", "G", "1100"]),
+            Some(&["rs1".to_string()][..])
+        );
+        assert_eq!(
+            dbsnp.lookup(["X", "200", "C", "T", "1200"]),
+            Some(&["rs2".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn read_raw_data_strips_a_bom_from_the_header() {
+        let raw = "\u{feff}rsid\tpvalue\nrs1\t0.5\n";
+        let d = read_raw_data('\t', raw.as_bytes());
+        assert_eq!(d.header, ["rsid", "pvalue"]);
+        assert_eq!(d.data, [["rs1", "0.5"]]);
+    }
+
+    #[test]
+    fn normalize_rsids_handles_mixed_formats() {
+        let rows: &[&[&str]] = &[
+            &["rs12345"],
+            &["RS12345"],
+            &["rs12345.1"],
+            &[" rs12345 "],
+            &["1:100:A:G"],
+            &["NA"],
+        ];
+        let mut d = data(&["rsid"], rows);
+        normalize_rsids(
+            &ctx(legend(&[]), &["--rsid-normalization", "drop-other"]),
+            &mut d,
+            "raw",
+        );
+        let rsids = d.col("rsid").collect::<Vec<_>>();
+        assert_eq!(rsids, [
+            "rs12345", "rs12345", "rs12345", "rs12345", "NA", "NA"
+        ]);
+        let mut d = data(&["rsid"], rows);
+        normalize_rsids(
+            &ctx(legend(&[]), &["--rsid-normalization", "keep-other"]),
+            &mut d,
+            "raw",
+        );
+        assert_eq!(d.data[4], ["1:100:A:G"]);
+    }
+
+    #[test]
+    fn header_only_input_is_an_error_unless_allowed() {
+        let path = temp_path("header_only.txt");
+        write_file(&path, 1, |w| {
+            w.write_all(b"SNP\tCHR\tBP\tA2\tA1\n").unwrap()
+        });
+        let path = path.to_string_lossy();
+        let sheet = legend(&[
+            ("rsid", "SNP"),
+            ("chr", "CHR"),
+            ("pos", "BP"),
+            ("ref", "A2"),
+            ("alt", "A1"),
+            ("file_path", &path),
+        ]);
+        let strict = ctx(sheet.clone(), &["--raw-path-base", "absolute"]);
+        assert!(matches!(
+            preformat(&strict),
+            Err(PipelineError::EmptyInput(_))
+        ));
+        let allowed = ctx(sheet, &["--raw-path-base", "absolute", "--allow-empty"]);
+        assert!(preformat(&allowed).unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn chain_pair_flag_overrides_the_conventional_name() {
+        let path = temp_path("GRCh38_to_GRCh37.chain.gz");
+        write_file(&path, 1, |_| {});
+        let path = path.to_string_lossy();
+        let ctx = ctx(legend(&[]), &["--chain-hg38-to-hg19", &path]);
+        assert_eq!(ctx.chain_path("hg38", "hg19").unwrap(), Path::new(&*path));
+        assert!(matches!(
+            ctx.chain_path("hg19", "hg38"),
+            Err(PipelineError::ChainMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn select_copies_columns_in_order_and_fills_missing_with_na() {
+        let d = data(&["a", "b", "c"], &[&["1", "2", "3"], &["4", "5", "6"]]);
+        let selected = d.select(&["c", "x", "a"]);
+        assert_eq!(selected.header, ["c", "x", "a"]);
+        assert_eq!(selected.data, [["3", "NA", "1"], ["6", "NA", "4"]]);
+        // the source is left untouched
+        assert_eq!(d.header, ["a", "b", "c"]);
+        assert_eq!(d.data[0], ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn rename_changes_only_the_matching_column() {
+        let mut d = data(&["a", "b"], &[&["1", "2"]]);
+        d.rename("b", "c");
+        d.rename("x", "y");
+        assert_eq!(d.header, ["a", "c"]);
+        assert_eq!(d.data, [["1", "2"]]);
+    }
+
+    #[test]
+    fn orient_to_reference_complements_alleles_on_the_other_strand() {
+        let ctx = ctx(legend(&[]), &[]);
+        let d = data(&["ref", "alt", "effect_size", "EAF", "harmonization"], &[
+            &["A", "G", "0.5", "0.2", "unmatched"],
+        ]);
+        let allele_cols = AlleleCols::new(&d);
+        let harmonization = d.idx("harmonization");
+        let orient = |n: &str| {
+            let mut r = d.data[0].clone();
+            orient_to_reference(&ctx, &allele_cols, harmonization, &mut r, n).map(|c| (c, r))
+        };
+        // the reference base is the complement of the ref allele
+        let (complemented, r) = orient("T").unwrap();
+        assert!(complemented);
+        assert_eq!(r, ["T", "C", "0.5", "0.2", "strand_flipped"]);
+        // the reference base is the complement of the alt allele
+        let (complemented, r) = orient("C").unwrap();
+        assert!(complemented);
+        assert_eq!(r, ["C", "T", "-0.5", "0.8", "strand_ref_flipped"]);
+        // matches without complementing
+        let (complemented, r) = orient("G").unwrap();
+        assert!(!complemented);
+        assert_eq!(r[4], "ref_flipped");
+        assert!(!orient("A").unwrap().0);
+        assert!(orient("N").is_none());
+    }
+
+    #[test]
+    fn kept_other_build_positions_are_validated_and_skip_liftover() {
+        let path = temp_path("with_hg38.txt");
+        write_file(&path, 1, |w| {
+            w.write_all(b"SNP\tCHR\tBP\tA2\tA1\tBETA\tpos_hg38\n")
+                .unwrap();
+            w.write_all(b"rs1\t1\t100\tA\tG\t0.1\t1100\n").unwrap();
+            w.write_all(b"rs2\t1\t200\tC\tT\t0.2\t.\n").unwrap();
+        });
+        let path = path.to_string_lossy();
+        let sheet = legend(&[
+            ("rsid", "SNP"),
+            ("chr", "CHR"),
+            ("pos", "BP"),
+            ("ref", "A2"),
+            ("alt", "A1"),
+            ("effect_size", "BETA"),
+            ("file_path", &path),
+        ]);
+        let args = ["--raw-path-base", "absolute", "--keep-columns", "pos_hg38"];
+        let ctx = ctx(sheet, &args);
+        let raw_data = preformat(&ctx).unwrap();
+        assert_eq!(raw_data.col("pos_hg38").collect::<Vec<_>>(), ["1100"]);
+        let lifted = liftover(&ctx, &raw_data).unwrap();
+        assert!(lifted.hg38.is_none());
+        assert_eq!(
+            ctx.report.0.lock().unwrap()["liftover_skipped"],
+            serde_json::json!(true)
+        );
+    }
+
+    #[test]
+    fn missing_other_build_positions_are_not_kept() {
+        let path = temp_path("without_hg38.txt");
+        write_file(&path, 1, |w| {
+            w.write_all(b"SNP\tCHR\tBP\tA2\tA1\tBETA\n").unwrap();
+            w.write_all(b"rs1\t1\t100\tA\tG\t0.1\n").unwrap();
+        });
+        let path = path.to_string_lossy();
+        let sheet = legend(&[
+            ("rsid", "SNP"),
+            ("chr", "CHR"),
+            ("pos", "BP"),
+            ("ref", "A2"),
+            ("alt", "A1"),
+            ("effect_size", "BETA"),
+            ("file_path", &path),
+        ]);
+        let args = ["--raw-path-base", "absolute", "--keep-columns", "pos_hg38"];
+        let raw_data = preformat(&ctx(sheet, &args)).unwrap();
+        assert!(raw_data.idx_opt("pos_hg38").is_none());
+    }
+
+    #[test]
+    fn streamed_output_matches_the_collected_output() {
+        let merged = data(
+            &[
+                "rsid",
+                "unique_id",
+                "chr_hg38",
+                "ref",
+                "alt",
+                "gnomAD_AF_EUR",
+                "harmonization",
+                "source",
+            ],
+            &[
+                &[
+                    "rs1",
+                    "1_100_A_G",
+                    "1",
+                    "A",
+                    "G",
+                    "0.1",
+                    "NA",
+                    "dbsnp_matched",
+                ],
+                &[
+                    "rs2",
+                    "X_200_C_T",
+                    "X",
+                    "C",
+                    "T",
+                    "0.2",
+                    "NA",
+                    "dbsnp_matched",
+                ],
+            ],
+        );
+        let ref_checked = vec![strings(&[
+            "NA",
+            "2_300_T_C",
+            "2",
+            "T",
+            "C",
+            "NA",
+            "ref_flipped",
+            "ref_checked",
+        ])];
+        let args = [
+            "--primary-af",
+            "EUR",
+            "--chr-output",
+            "chr-prefix",
+            "--output-meta",
+            "study=x",
+        ];
+        let mut ctx = ctx(legend(&[]), &args);
+        let streamed = temp_path("streamed.txt");
+        ctx.output_file = streamed.to_string_lossy().to_string();
+        stream_output(&ctx, merged.clone(), ref_checked.clone()).unwrap();
+        let collected = temp_path("collected.txt");
+        ctx.output_file = collected.to_string_lossy().to_string();
+        let mut final_data = merged;
+        final_data.data.extend(ref_checked);
+        write_output(&ctx, final_data).unwrap();
+        let streamed = std::fs::read_to_string(streamed).unwrap();
+        assert_eq!(streamed, std::fs::read_to_string(collected).unwrap());
+        assert!(streamed.contains("rs2\tX_200_C_T\tchrX\tC\tT\t0.2\t0.2\n"));
+    }
+
+    #[test]
+    fn reorder_moves_columns_and_fills_missing_with_na() {
+        let mut d = data(&["a", "b", "c"], &[&["1", "2", "3"], &["4", "5", "6"]]);
+        d.reorder(&["c", "x", "a"]);
+        assert_eq!(d.header, ["c", "x", "a"]);
+        assert_eq!(d.data, [["3", "NA", "1"], ["6", "NA", "4"]]);
+    }
 }